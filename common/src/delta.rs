@@ -0,0 +1,506 @@
+use crate::{
+    ActiveItem, ActiveItemKind, ClientId, PlayerState, ServerMessage, map::TrackPosition, types::*,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// states are sent as whole numbers in these steps
+const POS_STEP: f32 = 1.0 / 128.0;
+const VEL_STEP: f32 = 1.0 / 32.0;
+const ANGLE_STEP: f32 = 1.0 / 8.0;
+const JUMP_STEP: f32 = 1.0 / 1024.0;
+const PROGRESS_STEP: f32 = 1.0 / 1024.0;
+
+pub type ItemId = u32;
+
+// the fields of a quantized state that changed since the last update, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    changed: u16,
+    values: Vec<i32>,
+}
+
+impl Delta {
+    // none if nothing changed
+    fn between<const N: usize>(old: &[i32; N], new: &[i32; N]) -> Option<Self> {
+        let mut delta = Delta {
+            changed: 0,
+            values: Vec::new(),
+        };
+        for (i, (old, new)) in old.iter().zip(new).enumerate() {
+            if old != new {
+                delta.changed |= 1 << i;
+                delta.values.push(new.wrapping_sub(*old));
+            }
+        }
+        (delta.changed != 0).then_some(delta)
+    }
+
+    fn apply<const N: usize>(&self, base: &[i32; N]) -> [i32; N] {
+        let mut values = self.values.iter();
+        let mut result = *base;
+        for (i, value) in result.iter_mut().enumerate() {
+            if self.changed & (1 << i) != 0 {
+                *value = value.wrapping_add(values.next().copied().unwrap_or(0));
+            }
+        }
+        result
+    }
+}
+
+fn quantize(value: f32, step: f32) -> i32 {
+    (value / step).round() as i32
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct QuantizedPlayer([i32; 9]);
+
+impl QuantizedPlayer {
+    fn new(state: &PlayerState) -> Self {
+        Self([
+            quantize(state.pos.x, POS_STEP),
+            quantize(state.pos.y, POS_STEP),
+            quantize(state.vel, VEL_STEP),
+            quantize(state.rot, ANGLE_STEP),
+            quantize(state.visual_rot, ANGLE_STEP),
+            quantize(state.jump_height, JUMP_STEP),
+            state.track_pos.lap as i32,
+            state.track_pos.segment as i32,
+            quantize(state.track_pos.progress, PROGRESS_STEP),
+        ])
+    }
+
+    fn state(&self) -> PlayerState {
+        let [
+            x,
+            y,
+            vel,
+            rot,
+            visual_rot,
+            jump_height,
+            lap,
+            segment,
+            progress,
+        ] = self.0;
+        PlayerState {
+            pos: Vec2::new(x as f32 * POS_STEP, y as f32 * POS_STEP),
+            vel: vel as f32 * VEL_STEP,
+            rot: rot as f32 * ANGLE_STEP,
+            visual_rot: visual_rot as f32 * ANGLE_STEP,
+            track_pos: TrackPosition {
+                lap: lap.max(0) as usize,
+                segment: segment.max(0) as usize,
+                progress: progress as f32 * PROGRESS_STEP,
+            },
+            jump_height: jump_height as f32 * JUMP_STEP,
+        }
+    }
+}
+
+// the kind of an item never changes, only where it is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct QuantizedItem([i32; 4]);
+
+impl QuantizedItem {
+    fn new(item: &ActiveItem) -> Self {
+        let roll = match item.kind {
+            ActiveItemKind::GreenShell { roll } | ActiveItemKind::RedShell { roll } => roll,
+            ActiveItemKind::Banana => 0.0,
+        };
+        Self([
+            quantize(item.pos.x, POS_STEP),
+            quantize(item.pos.y, POS_STEP),
+            quantize(item.rot, ANGLE_STEP),
+            quantize(roll, ANGLE_STEP),
+        ])
+    }
+
+    fn item(&self, kind: &ActiveItemKind) -> ActiveItem {
+        let [x, y, rot, roll] = self.0;
+        let roll = roll as f32 * ANGLE_STEP;
+        ActiveItem {
+            pos: Vec2::new(x as f32 * POS_STEP, y as f32 * POS_STEP),
+            rot: rot as f32 * ANGLE_STEP,
+            kind: match kind {
+                ActiveItemKind::GreenShell { .. } => ActiveItemKind::GreenShell { roll },
+                ActiveItemKind::RedShell { .. } => ActiveItemKind::RedShell { roll },
+                ActiveItemKind::Banana => ActiveItemKind::Banana,
+            },
+        }
+    }
+}
+
+// what one client knows about the race. the server keeps one for every client to work out
+// what has to be sent, and the client keeps its own to apply what it receives, so both end
+// up with the same values
+#[derive(Debug, Clone, Default)]
+pub struct RaceView {
+    players: HashMap<ClientId, QuantizedPlayer>,
+    items: HashMap<ItemId, (ActiveItemKind, QuantizedItem)>,
+    coins: HashMap<ClientId, u32>,
+}
+
+impl RaceView {
+    // builds the update that brings this view up to date. players and items at positions that
+    // `include` rejects keep their old state for now, but still appear and disappear. coin
+    // counts change rarely and are always sent.
+    // keyframes start over from nothing, so they also fix views that went out of sync
+    pub fn encode<'a>(
+        &mut self,
+        race_time: f32,
+        keyframe: bool,
+        players: impl IntoIterator<Item = (ClientId, &'a PlayerState)>,
+        coins: &[(ClientId, u32)],
+        items: &[(ItemId, ActiveItem)],
+        include: impl Fn(Vec2) -> bool,
+    ) -> ServerMessage {
+        if keyframe {
+            self.players.clear();
+            self.items.clear();
+            self.coins.clear();
+        }
+
+        let mut player_deltas = Vec::new();
+        for (id, state) in players {
+            let known = self.players.get(&id);
+            if known.is_some() && !include(state.pos) {
+                continue;
+            }
+
+            let old = known.copied().unwrap_or_default();
+            let new = QuantizedPlayer::new(state);
+            self.players.insert(id, new);
+            if let Some(delta) = Delta::between(&old.0, &new.0) {
+                player_deltas.push((id, delta));
+            }
+        }
+
+        let changed_coins = coins
+            .iter()
+            .filter(|(id, count)| self.coins.insert(*id, *count) != Some(*count))
+            .copied()
+            .collect();
+
+        let mut spawned_items = Vec::new();
+        let mut moved_items = Vec::new();
+        for (id, item) in items {
+            let new = QuantizedItem::new(item);
+            match self.items.get_mut(id) {
+                Some((_, old)) => {
+                    if !include(item.pos) {
+                        continue;
+                    }
+                    if let Some(delta) = Delta::between(&old.0, &new.0) {
+                        moved_items.push((*id, delta));
+                    }
+                    *old = new;
+                }
+                None => {
+                    self.items.insert(*id, (item.kind.clone(), new));
+                    spawned_items.push((*id, new.item(&item.kind)));
+                }
+            }
+        }
+
+        let despawned_items: Vec<_> = self
+            .items
+            .keys()
+            .filter(|id| !items.iter().any(|(i, _)| i == *id))
+            .copied()
+            .collect();
+        for id in &despawned_items {
+            self.items.remove(id);
+        }
+
+        ServerMessage::RaceDelta {
+            race_time,
+            keyframe,
+            players: player_deltas,
+            coins: changed_coins,
+            spawned_items,
+            moved_items,
+            despawned_items,
+        }
+    }
+
+    // applies a RaceDelta, returns the players that changed. players that aren't known yet start
+    // from nothing, the same as in encode. moves of unknown items are dropped
+    pub fn apply(
+        &mut self,
+        keyframe: bool,
+        players: Vec<(ClientId, Delta)>,
+        spawned_items: Vec<(ItemId, ActiveItem)>,
+        moved_items: Vec<(ItemId, Delta)>,
+        despawned_items: Vec<ItemId>,
+    ) -> Vec<(ClientId, PlayerState)> {
+        if keyframe {
+            self.players.clear();
+            self.items.clear();
+        }
+
+        let mut changed = Vec::new();
+        for (id, delta) in players {
+            let base = self.players.get(&id).copied().unwrap_or_default();
+            let new = QuantizedPlayer(delta.apply(&base.0));
+            self.players.insert(id, new);
+            changed.push((id, new.state()));
+        }
+
+        for (id, item) in spawned_items {
+            self.items
+                .insert(id, (item.kind.clone(), QuantizedItem::new(&item)));
+        }
+        for (id, delta) in moved_items {
+            if let Some((_, item)) = self.items.get_mut(&id) {
+                *item = QuantizedItem(delta.apply(&item.0));
+            }
+        }
+        for id in despawned_items {
+            self.items.remove(&id);
+        }
+
+        changed
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = ActiveItem> + '_ {
+        self.items.values().map(|(kind, item)| item.item(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what the client ends up with after an update went over the network
+    struct Sent {
+        players: Vec<(ClientId, PlayerState)>,
+        player_deltas: usize,
+        spawned: usize,
+        moved: usize,
+        despawned: usize,
+        size: usize,
+    }
+
+    fn send(
+        server: &mut RaceView,
+        client: &mut RaceView,
+        keyframe: bool,
+        players: &[(ClientId, PlayerState)],
+        items: &[(ItemId, ActiveItem)],
+        include: impl Fn(Vec2) -> bool,
+    ) -> Sent {
+        let msg = server.encode(
+            0.0,
+            keyframe,
+            players.iter().map(|(id, state)| (*id, state)),
+            &[],
+            items,
+            include,
+        );
+        let bytes = msg.to_bytes().unwrap();
+        let ServerMessage::RaceDelta {
+            keyframe,
+            players,
+            spawned_items,
+            moved_items,
+            despawned_items,
+            ..
+        } = ServerMessage::from_bytes(&bytes).unwrap()
+        else {
+            panic!("expected a race delta");
+        };
+
+        let (player_deltas, spawned, moved, despawned) = (
+            players.len(),
+            spawned_items.len(),
+            moved_items.len(),
+            despawned_items.len(),
+        );
+        Sent {
+            players: client.apply(
+                keyframe,
+                players,
+                spawned_items,
+                moved_items,
+                despawned_items,
+            ),
+            player_deltas,
+            spawned,
+            moved,
+            despawned,
+            size: bytes.len(),
+        }
+    }
+
+    fn player(x: f32, rot: f32) -> PlayerState {
+        PlayerState {
+            pos: Vec2::new(x, 2.0),
+            vel: 12.5,
+            rot,
+            visual_rot: rot + 10.0,
+            jump_height: 0.1,
+            track_pos: TrackPosition {
+                lap: 1,
+                segment: 3,
+                progress: 0.5,
+            },
+        }
+    }
+
+    fn shell(x: f32) -> ActiveItem {
+        ActiveItem {
+            pos: Vec2::new(x, 0.0),
+            rot: 90.0,
+            kind: ActiveItemKind::GreenShell { roll: 40.0 },
+        }
+    }
+
+    fn assert_close(received: &PlayerState, sent: &PlayerState) {
+        assert!(received.pos.distance(sent.pos) <= POS_STEP);
+        assert!((received.vel - sent.vel).abs() <= VEL_STEP / 2.0);
+        assert!((received.rot - sent.rot).abs() <= ANGLE_STEP / 2.0);
+        assert!((received.visual_rot - sent.visual_rot).abs() <= ANGLE_STEP / 2.0);
+        assert!((received.jump_height - sent.jump_height).abs() <= JUMP_STEP / 2.0);
+        assert_eq!(received.track_pos.lap, sent.track_pos.lap);
+        assert_eq!(received.track_pos.segment, sent.track_pos.segment);
+        assert!((received.track_pos.progress - sent.track_pos.progress).abs() <= PROGRESS_STEP);
+    }
+
+    #[test]
+    fn keyframe_and_delta() {
+        let (a, b) = (ClientId::new(1), ClientId::new(2));
+        let (mut server, mut client) = (RaceView::default(), RaceView::default());
+        let mut players = vec![(a, player(1.0, 0.0)), (b, player(-3.0, 90.0))];
+
+        let keyframe = send(&mut server, &mut client, true, &players, &[], |_| true);
+        assert_eq!(keyframe.player_deltas, 2);
+        for (id, state) in &players {
+            let (_, received) = keyframe.players.iter().find(|(i, _)| i == id).unwrap();
+            assert_close(received, state);
+        }
+
+        // only what changed is sent
+        players[0].1.pos.x += 0.5;
+        let delta = send(&mut server, &mut client, false, &players, &[], |_| true);
+        assert_eq!(delta.player_deltas, 1);
+        assert_eq!(delta.players[0].0, a);
+        assert_close(&delta.players[0].1, &players[0].1);
+        assert!(delta.size < keyframe.size);
+
+        let unchanged = send(&mut server, &mut client, false, &players, &[], |_| true);
+        assert_eq!(unchanged.player_deltas, 0);
+
+        // keyframes send everything again
+        let keyframe = send(&mut server, &mut client, true, &players, &[], |_| true);
+        assert_eq!(keyframe.player_deltas, 2);
+    }
+
+    #[test]
+    fn items_spawn_move_and_despawn() {
+        let (mut server, mut client) = (RaceView::default(), RaceView::default());
+
+        let spawned = send(
+            &mut server,
+            &mut client,
+            true,
+            &[],
+            &[(7, shell(1.0))],
+            |_| true,
+        );
+        assert_eq!(spawned.spawned, 1);
+        let items: Vec<_> = client.items().collect();
+        assert_eq!(items.len(), 1);
+        assert!(matches!(items[0].kind, ActiveItemKind::GreenShell { .. }));
+
+        let moved = send(
+            &mut server,
+            &mut client,
+            false,
+            &[],
+            &[(7, shell(4.0))],
+            |_| true,
+        );
+        assert_eq!((moved.spawned, moved.moved), (0, 1));
+        let item = client.items().next().unwrap();
+        assert!((item.pos.x - 4.0).abs() <= POS_STEP);
+        assert!((item.rot - 90.0).abs() <= ANGLE_STEP);
+
+        let despawned = send(&mut server, &mut client, false, &[], &[], |_| true);
+        assert_eq!(despawned.despawned, 1);
+        assert_eq!(client.items().count(), 0);
+    }
+
+    #[test]
+    fn filtered_players_keep_their_state() {
+        let (a, b) = (ClientId::new(1), ClientId::new(2));
+        let (mut server, mut client) = (RaceView::default(), RaceView::default());
+        let near = |pos: Vec2| pos.x < 10.0;
+        let mut players = vec![(a, player(1.0, 0.0)), (b, player(20.0, 0.0))];
+
+        // players the client doesn't know yet are always sent
+        let first = send(&mut server, &mut client, true, &players, &[], near);
+        assert_eq!(first.player_deltas, 2);
+
+        players[1].1.pos.x = 30.0;
+        let filtered = send(&mut server, &mut client, false, &players, &[], near);
+        assert_eq!(filtered.player_deltas, 0);
+
+        // once in range again the delta is based on the state the client still has
+        players[1].1.pos.x = 5.0;
+        let back = send(&mut server, &mut client, false, &players, &[], near);
+        assert_eq!(back.player_deltas, 1);
+        assert_eq!(back.players[0].0, b);
+        assert_close(&back.players[0].1, &players[1].1);
+    }
+
+    #[test]
+    fn player_joins_between_keyframes() {
+        let (a, b) = (ClientId::new(1), ClientId::new(2));
+        let (mut server, mut client) = (RaceView::default(), RaceView::default());
+        let mut players = vec![(a, player(1.0, 0.0))];
+        send(&mut server, &mut client, true, &players, &[], |_| true);
+
+        players.push((b, player(-4.0, 45.0)));
+        let joined = send(&mut server, &mut client, false, &players, &[], |_| true);
+        assert_eq!(joined.player_deltas, 1);
+        assert_eq!(joined.players[0].0, b);
+        assert_close(&joined.players[0].1, &players[1].1);
+
+        // later deltas build on what the client got when the player appeared
+        players[1].1.pos.y += 1.5;
+        players[1].1.rot -= 20.0;
+        let moved = send(&mut server, &mut client, false, &players, &[], |_| true);
+        assert_eq!(moved.players.len(), 1);
+        assert_close(&moved.players[0].1, &players[1].1);
+    }
+
+    #[test]
+    fn large_rotations() {
+        let id = ClientId::new(1);
+        let (mut server, mut client) = (RaceView::default(), RaceView::default());
+
+        // karts start at 18000 degrees and keep turning from there
+        let mut state = player(0.0, 18000.0);
+        let mut received = send(
+            &mut server,
+            &mut client,
+            true,
+            &[(id, state.clone())],
+            &[],
+            |_| true,
+        );
+        for i in 0..1000 {
+            state.rot += if i % 3 == 0 { -7.3 } else { 11.9 };
+            state.visual_rot = state.rot - 4.2;
+            received = send(
+                &mut server,
+                &mut client,
+                false,
+                &[(id, state.clone())],
+                &[],
+                |_| true,
+            );
+        }
+        assert_close(&received.players[0].1, &state);
+    }
+}
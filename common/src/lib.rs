@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
 
+pub mod delta;
 pub mod map;
+pub mod physics;
+pub mod replay;
 pub mod types;
 pub use map::TrackPosition;
 use types::*;
 
+// bump whenever ClientMessage or ServerMessage change, so clients that were loaded before a
+// deploy are told to reload instead of sending and reading garbage
+pub const PROTOCOL_VERSION: u32 = 5;
+
 pub const TICKS_PER_SECOND: f32 = 60.0;
 pub const COUNTDOWN_DURATION: f32 = 3.0;
+pub const LAP_COUNT: usize = 3;
+// other karts are shown this far in the past, so there is usually a newer update to move towards
+pub const INTERPOLATION_DELAY: f32 = 0.1;
 
 pub const MAP_SCALE: f32 = 20.0;
 
@@ -20,7 +30,45 @@ pub fn world_coord_to_map(pos: Vec2) -> Vec2 {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
-    DuplicateLogin,
+    // the client speaks a different protocol version and has to be reloaded. this has to stay
+    // the first message so clients of every version can read it
+    OutdatedClient {
+        server_version: u32,
+    },
+
+    // there are too many connections from the player's ip address
+    TooManyConnections,
+
+    // the player has been put into a room, private rooms come with their join code.
+    // the token lets the player resume their session after losing the connection
+    JoinedRoom {
+        code: Option<String>,
+        token: String,
+    },
+    // the session to resume has already been removed
+    SessionExpired,
+    // the session has been resumed, catch up with what happened in the meantime
+    Resync {
+        race: Option<RaceResync>,
+    },
+    // the requested private room doesn't exist (anymore)
+    RoomNotFound,
+    // the requested private room has no space left
+    RoomFull,
+    // the name the player shows up as, which might have been changed to be unique in the room
+    NameAccepted(String),
+    // the player can't use the name they registered with, the connection is closed after this
+    NameRejected(NameRejection),
+    // the player was removed from the server by an admin
+    Kicked,
+    // message from the server admins to show to everyone
+    Announcement(String),
+
+    // vote on which map is played next, duration is in seconds
+    MapVote {
+        candidates: Vec<MapCandidate>,
+        duration: f32,
+    },
 
     // server is preparing a new round
     PrepareRound {
@@ -41,6 +89,11 @@ pub enum ServerMessage {
         params: RoundInitParams,
     },
 
+    // a round is being played without the player, load the map and watch it
+    Spectate {
+        params: SpectateParams,
+    },
+
     // countdown has started
     StartCountdown,
 
@@ -54,10 +107,17 @@ pub enum ServerMessage {
         state: bool,
     },
 
+    // the item box the player drove through gave them an item
+    ReceivedItem {
+        item: ItemKind,
+    },
+
     // update the positions of all players
     RaceUpdate {
         race_time: f32,
         players: Vec<(ClientId, PlayerState)>,
+        // coin counts are kept by the server, players only pick them up
+        coins: Vec<(ClientId, u32)>,
 
         active_items: Vec<ActiveItem>,
     },
@@ -67,16 +127,35 @@ pub enum ServerMessage {
         player: ClientId,
     },
 
-    // PlayerCollision {
-    //     depth: f32,
-    //     other_velocity: f32,
-    //     other_rotation: f32,
-    //     normal: Vec2,
-    // },
-
-    // round has ended, show placements
+    // round has ended, show placements and the fastest finish ever driven on the map
     EndRound {
         placements: Vec<Placement>,
+        record: Option<RaceRecord>,
+    },
+
+    // what changed since the last RaceUpdate or RaceDelta sent to the player, see delta::RaceView.
+    // sent during races instead of RaceUpdate, which is still what replays are made of
+    RaceDelta {
+        race_time: f32,
+        keyframe: bool,
+        players: Vec<(ClientId, delta::Delta)>,
+        // only the counts that changed, including the player's own
+        coins: Vec<(ClientId, u32)>,
+
+        spawned_items: Vec<(delta::ItemId, ActiveItem)>,
+        moved_items: Vec<(delta::ItemId, delta::Delta)>,
+        despawned_items: Vec<delta::ItemId>,
+    },
+
+    // the last player update wasn't plausible, the player's kart is moved back to this state
+    Correction(PlayerState),
+
+    // the player's kart ran into another one, the server works out how both bounce off
+    Bumped(physics::Bump),
+
+    // a shell ran into another item and both exploded
+    ItemsCollided {
+        pos: Vec2,
     },
 }
 
@@ -91,18 +170,43 @@ impl ServerMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
-    Register { name: String }, // register a new player
-    LoadedMap,                 // client has loaded the map
+    // register a new player, spectators only watch the races and never take part.
+    // the version has to stay the first field of the first two messages, see peek_version
+    Register {
+        version: u32,
+        name: String,
+        room: RoomRequest,
+        spectate: bool,
+    },
+    Resume {
+        version: u32,
+        token: String,
+    }, // pick up a session after reconnecting
+    LoadedMap, // client has loaded the map
+
+    VoteMap(usize), // vote for one of the candidates in the current map vote
 
-    PickUp { kind: PickupKind, index: usize },
+    PickUp {
+        kind: PickupKind,
+        index: usize,
+    },
 
-    UseItem(ActiveItemKind), // player has used an item
+    UseItem(ItemKind), // player has used the item they were given
 
     PlayerUpdate(PlayerState), // update the player's position
 
-    FinishRound { race_time: f32 }, // player has finished the round
+    FinishRound {
+        race_time: f32,
+    }, // player has finished the round (the server keeps its own time)
 }
 impl ClientMessage {
+    // protocol version of a Register or Resume message, even if the rest of it can't be read
+    // because it was sent by a client on another version
+    pub fn peek_version(bytes: &[u8]) -> Option<u32> {
+        let (variant, version): (u32, u32) = postcard::take_from_bytes(bytes).ok()?.0;
+        matches!(variant, 0 | 1).then_some(version)
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, postcard::Error> {
         postcard::from_bytes(bytes)
     }
@@ -111,6 +215,20 @@ impl ClientMessage {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapCandidate {
+    pub name: String,
+    pub author: String,
+    pub thumbnail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RoomRequest {
+    Public,
+    CreatePrivate,
+    JoinPrivate(String),
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PlayerState {
     pub pos: Vec2,
@@ -121,6 +239,19 @@ pub struct PlayerState {
     pub jump_height: f32,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaceResync {
+    // none while the countdown is still running
+    pub race_time: Option<f32>,
+    pub state: PlayerState,
+    pub item: Option<ItemKind>,
+    pub coins: u32,
+    pub finished: bool,
+
+    pub coin_states: Vec<bool>,
+    pub item_box_states: Vec<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoundInitParams {
     pub client_id: ClientId,
@@ -128,6 +259,17 @@ pub struct RoundInitParams {
     pub players: Vec<(ClientId, String)>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpectateParams {
+    pub map: String,
+    pub players: Vec<(ClientId, String)>,
+    // None while the countdown is still running
+    pub race_time: Option<f32>,
+
+    pub coin_states: Vec<bool>,
+    pub item_box_states: Vec<bool>,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum PickupKind {
     Coin,
@@ -148,7 +290,7 @@ pub enum ActiveItemKind {
     Banana,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ItemKind {
     GreenShell,
     RedShell,
@@ -162,7 +304,23 @@ pub struct Placement {
     pub finish_time: Option<f32>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaceRecord {
+    pub name: String,
+    pub time: f32,
+    // how long each lap took
+    pub laps: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum NameRejection {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    // the name contains a word that isn't allowed on the server
+    NotAllowed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ClientId(u32);
 impl ClientId {
     pub fn new(id: u32) -> Self {
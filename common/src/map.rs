@@ -53,6 +53,18 @@ pub enum MapLoadError {
     MissingAsset(AssetId, String),
 }
 
+#[derive(Debug, Error)]
+pub enum MapValidationError {
+    #[error("Missing background image")]
+    MissingBackground,
+    #[error("Missing coin image")]
+    MissingCoin,
+    #[error("Missing item box image")]
+    MissingItemBox,
+    #[error("Track needs at least 2 points")]
+    TrackTooShort,
+}
+
 #[derive(Debug)]
 pub enum MapSaveError {
     IoError(std::io::Error),
@@ -169,6 +181,26 @@ impl Map {
         Ok(data)
     }
 
+    // checks everything a map needs to be playable
+    pub fn validate(&self) -> Result<(), MapValidationError> {
+        let has_asset = |id: Option<AssetId>| id.is_some_and(|id| self.asset(id).is_some());
+
+        if !has_asset(self.background) {
+            return Err(MapValidationError::MissingBackground);
+        }
+        if !has_asset(self.coin) {
+            return Err(MapValidationError::MissingCoin);
+        }
+        if !has_asset(self.item_box) {
+            return Err(MapValidationError::MissingItemBox);
+        }
+        if self.track.path.len() < 2 {
+            return Err(MapValidationError::TrackTooShort);
+        }
+
+        Ok(())
+    }
+
     pub fn save<W: Write + Seek>(&self, map: W) -> Result<(), MapSaveError> {
         let mut map = Builder::new(map);
 
@@ -194,6 +226,9 @@ pub struct Metadata {
     pub name: String,
     pub description: String,
     pub author: String,
+    // image shown when voting for the map, relative to the map file
+    #[serde(default)]
+    pub thumbnail: Option<String>,
 }
 
 impl Default for Metadata {
@@ -202,6 +237,7 @@ impl Default for Metadata {
             name: "Untitled Map".to_string(),
             description: "".to_string(),
             author: "".to_string(),
+            thumbnail: None,
         }
     }
 }
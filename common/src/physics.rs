@@ -0,0 +1,575 @@
+use crate::{
+    MAP_SCALE, PlayerState,
+    map::{Map, Track, TrackPosition},
+    map_coord_to_world,
+    types::*,
+    world_coord_to_map,
+};
+use nalgebra::Point2;
+use parry2d::{
+    math::{Isometry, Vector},
+    query::{self, Ray, RayCast},
+    shape::{Ball, Polyline},
+    utils::point_in_poly2d,
+};
+use serde::{Deserialize, Serialize};
+
+pub const MOVE_ACCEL: f32 = 14.5;
+const COIN_BOOST: f32 = 2.0;
+// karts further back accelerate a little faster
+const POS_BOOST: f32 = 0.15;
+const STEER_ACCEL: f32 = 50.0;
+const DRIFT_ACCEL: f32 = 65.0;
+
+const BOOST_ACCEL: f32 = 15.0;
+pub const BOOST_DURATION: f32 = 0.8;
+const BOOST_KICK: f32 = 8.0;
+pub const HIT_DURATION: f32 = 1.5;
+
+// the fastest a kart gets with all coins from the back of the field, in world units per second
+const MAX_SPEED: f32 = MOVE_ACCEL + COIN_BOOST * 1.5 + POS_BOOST * 25.0;
+pub const MAX_BOOST_SPEED: f32 = MAX_SPEED + BOOST_ACCEL + BOOST_KICK;
+pub const MAX_JUMP_HEIGHT: f32 = 0.15;
+
+fn coin_boost(coins: u32) -> f32 {
+    match coins {
+        10 => COIN_BOOST * 1.5,
+        c => (c as f32 / 10.0) * COIN_BOOST,
+    }
+}
+
+// the fastest a kart with this many coins gets from the back of the field
+pub fn max_speed(coins: u32, boosting: bool) -> f32 {
+    let speed = MOVE_ACCEL + coin_boost(coins) + POS_BOOST * 25.0;
+    if boosting {
+        speed + BOOST_ACCEL + BOOST_KICK
+    } else {
+        speed
+    }
+}
+
+// how long after pressing drift without steering a direction can still be picked
+const DRIFT_QUEUE_TIME: f32 = 0.1;
+// how long a kart can be on offroad before it starts sliding
+const OFFROAD_SLIDE_DELAY: f32 = 0.1;
+
+// in map units
+const KART_COLLIDER_RADIUS: f32 = 5.0;
+// karts bump into each other when they are closer than twice this, in world units
+pub const KART_BUMP_RADIUS: f32 = 8.0 / MAP_SCALE;
+const KART_MASS: f32 = 1.0;
+// how much of the speed karts drive into each other with they bounce off with
+const BUMP_RESTITUTION: f32 = 0.5;
+
+// the parts of a map that karts drive on and into, prepared once when the map is loaded.
+// positions passed in and out are in world coordinates
+#[derive(Default)]
+pub struct PhysicsMap {
+    track: Track,
+    colliders: Vec<Polyline>,
+    offroad: Vec<Vec<Point2<f32>>>,
+}
+
+impl std::fmt::Debug for PhysicsMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PhysicsMap").finish_non_exhaustive()
+    }
+}
+
+impl PhysicsMap {
+    pub fn new(map: &Map) -> Self {
+        // shapes are closed by going back to their first point
+        let closed = |shape: &[Vec2]| -> Vec<Point2<f32>> {
+            shape
+                .iter()
+                .chain(shape.first())
+                .map(|p| Point2::new(p.x, p.y))
+                .collect()
+        };
+
+        Self {
+            track: map.track.clone(),
+            colliders: map
+                .colliders
+                .iter()
+                .map(|c| Polyline::new(closed(&c.shape), None))
+                .collect(),
+            offroad: map.offroad.iter().map(|o| closed(&o.shape)).collect(),
+        }
+    }
+
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    // the walls, in map units
+    pub fn colliders(&self) -> &[Polyline] {
+        &self.colliders
+    }
+
+    pub fn is_offroad(&self, pos: Vec2) -> bool {
+        let pos = world_coord_to_map(pos);
+        let pos = Point2::new(pos.x, pos.y);
+        self.offroad
+            .iter()
+            .any(|offroad| point_in_poly2d(&pos, offroad))
+    }
+
+    // karts are kept out of walls, so driving from one position to the other never crosses one
+    pub fn crosses_wall(&self, from: Vec2, to: Vec2) -> bool {
+        let from = world_coord_to_map(from);
+        let to = world_coord_to_map(to);
+        if from == to {
+            return false;
+        }
+
+        let ray = Ray::new(
+            Point2::new(from.x, from.y),
+            Vector::new(to.x - from.x, to.y - from.y),
+        );
+        self.colliders
+            .iter()
+            .any(|wall| wall.intersects_ray(&Isometry::identity(), &ray, 1.0))
+    }
+
+    // pushes a kart at the given position out of the walls, returns none if it isn't touching any
+    pub fn push_out_of_walls(&self, pos: Vec2) -> Option<Vec2> {
+        let collider = Ball::new(KART_COLLIDER_RADIUS);
+        let collider_pos = Isometry::new(nalgebra::zero(), 0.0);
+        let pos_map = world_coord_to_map(pos);
+        let own_pos = Isometry::new(Vector::new(pos_map.x, pos_map.y), 0.0);
+
+        let mut new_pos = None;
+        for wall in &self.colliders {
+            if let Ok(Some(contact)) =
+                query::contact(&own_pos, &collider, &collider_pos, wall, nalgebra::zero())
+            {
+                let translation = Vec2::new(contact.normal2.x, contact.normal2.y) * contact.dist;
+                new_pos = Some(new_pos.unwrap_or(pos) - map_coord_to_world(translation));
+            }
+        }
+        new_pos
+    }
+}
+
+// everything that decides how a kart moves during a step
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KartInput {
+    // 1 is full throttle, negative values drive backwards
+    pub throttle: f32,
+    // -1 is full left, 1 is full right
+    pub steer: f32,
+    // held down while drifting
+    pub drift: bool,
+
+    pub coins: u32,
+    // 1 is first place
+    pub place: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DriftState {
+    #[default]
+    None,
+    Offroad,
+    Queued(f32), // time in s before queue is cancelled
+    Left,
+    Right,
+}
+
+impl DriftState {
+    fn update(&mut self, dt: f32) {
+        if let DriftState::Queued(time) = self {
+            *time -= dt;
+            if *time <= 0.0 {
+                *self = DriftState::None;
+            }
+        }
+    }
+
+    pub fn as_multiplier(&self) -> f32 {
+        match self {
+            DriftState::Left => -1.0,
+            DriftState::Right => 1.0,
+            DriftState::None | DriftState::Queued(_) | DriftState::Offroad => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KartState {
+    // world coordinates
+    pub pos: Vec2,
+    // degrees
+    pub rot: f32,
+    // y is the forward speed, x how fast the kart turns in degrees per second
+    pub velocity: Vec2,
+    pub track_pos: TrackPosition,
+    pub drift: DriftState,
+
+    drift_held: bool,
+    offroad_time: Option<f32>,
+    jump_progress: f32,
+    boost_time: f32,
+    hit_time: f32,
+}
+
+impl KartState {
+    pub fn new(pos: Vec2, rot: f32) -> Self {
+        Self {
+            pos,
+            rot,
+            velocity: Vec2::ZERO,
+            track_pos: TrackPosition::default(),
+            drift: DriftState::None,
+
+            drift_held: false,
+            offroad_time: None,
+            jump_progress: 1.0,
+            boost_time: 0.0,
+            hit_time: 0.0,
+        }
+    }
+
+    // picks up a kart from the state that was sent for it
+    pub fn from_player_state(state: &PlayerState) -> Self {
+        Self {
+            velocity: Vec2::new(0.0, state.vel),
+            track_pos: state.track_pos,
+            ..Self::new(state.pos, state.rot)
+        }
+    }
+
+    pub fn jump_height(&self) -> f32 {
+        f32::sin(self.jump_progress * std::f32::consts::PI) * MAX_JUMP_HEIGHT
+    }
+
+    // only once the kart has been slowed down, which doesn't happen while jumping or boosting
+    pub fn is_offroad(&self) -> bool {
+        self.offroad_time.is_some()
+    }
+
+    pub fn is_hit(&self) -> bool {
+        self.hit_time > 0.0
+    }
+
+    // returns false if the kart is still spinning from the last hit
+    pub fn hit(&mut self) -> bool {
+        if self.is_hit() {
+            return false;
+        }
+        self.hit_time = HIT_DURATION;
+        true
+    }
+
+    pub fn boost(&mut self) {
+        self.boost_time = BOOST_DURATION;
+        self.velocity.y += BOOST_KICK;
+    }
+
+    pub fn bump(&mut self, bump: &Bump, map: &PhysicsMap) {
+        (self.pos, self.velocity.y) = bump.apply(self.pos, self.velocity.y, map);
+    }
+
+    // moves the kart forward by dt seconds. the result only depends on the arguments,
+    // so the same inputs end up in the same place on every platform
+    pub fn step(&self, input: &KartInput, map: &PhysicsMap, dt: f32) -> Self {
+        let mut next = *self;
+
+        // drifting starts with a hop, in the direction the kart is steering
+        if input.drift && !self.drift_held {
+            next.jump_progress = 0.0;
+            if next.drift != DriftState::Offroad {
+                next.drift =
+                    drift_direction(input.steer).unwrap_or(DriftState::Queued(DRIFT_QUEUE_TIME));
+            }
+        } else if input.drift {
+            if let (DriftState::Queued(_), Some(direction)) =
+                (next.drift, drift_direction(input.steer))
+            {
+                next.drift = direction;
+            }
+        } else if self.drift_held {
+            next.drift = DriftState::None;
+        }
+        next.drift_held = input.drift;
+
+        let coin_boost = coin_boost(input.coins);
+
+        let boost = if next.boost_time > 0.0 {
+            next.boost_time -= dt;
+            BOOST_ACCEL
+        } else {
+            0.0
+        };
+
+        let mut move_accel = input.throttle
+            * (MOVE_ACCEL + coin_boost + POS_BOOST * (input.place as f32).min(25.0))
+            + boost;
+        let mut steer_accel = input.steer * STEER_ACCEL;
+
+        if map.is_offroad(self.pos) {
+            if next.boost_time <= 0.0 && (next.jump_progress >= 1.0 || next.offroad_time.is_some())
+            {
+                if next.velocity.y > MOVE_ACCEL * 0.75 {
+                    move_accel = 0.0;
+                } else {
+                    move_accel *= 0.5;
+                }
+                next.offroad_time.get_or_insert(0.0);
+            }
+
+            if let Some(time) = &mut next.offroad_time {
+                *time += dt;
+                if *time > OFFROAD_SLIDE_DELAY {
+                    next.drift = DriftState::Offroad;
+                }
+            }
+        } else {
+            next.offroad_time = None;
+            if next.drift == DriftState::Offroad {
+                next.drift = DriftState::None;
+            }
+        }
+
+        next.drift.update(dt);
+        steer_accel += next.drift.as_multiplier() * DRIFT_ACCEL;
+
+        if next.hit_time > 0.0 {
+            next.hit_time -= dt;
+            move_accel = 0.0;
+            steer_accel = 0.0;
+        }
+
+        next.velocity.y = f32::lerp(next.velocity.y, move_accel, dt * 2.0);
+        next.velocity.x = f32::lerp(next.velocity.x, steer_accel, dt * 4.0);
+
+        let forward = Vec2::new(self.rot.to_radians().cos(), self.rot.to_radians().sin());
+        let mut pos = self.pos + forward * next.velocity.y * dt;
+        next.rot = self.rot + next.velocity.x * dt;
+
+        if let Some(pushed) = map.push_out_of_walls(pos) {
+            pos = pushed;
+            next.velocity.y = 0.0;
+        }
+
+        map.track.calc_position(
+            world_coord_to_map(self.pos),
+            world_coord_to_map(pos),
+            &mut next.track_pos,
+        );
+        next.pos = pos;
+
+        next.jump_progress = (next.jump_progress + dt * 5.0).min(1.0);
+
+        next
+    }
+}
+
+// one kart bumping into another, as seen from one of the two
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bump {
+    // moves the karts apart so they don't overlap anymore
+    pub push: Vec2,
+    pub speed_change: f32,
+}
+
+impl Bump {
+    // returns the new position and forward speed of a kart. changes are added to what the kart
+    // is doing now, it may have moved on since the bump was worked out. the push is dropped if
+    // it would go through a wall
+    pub fn apply(&self, pos: Vec2, vel: f32, map: &PhysicsMap) -> (Vec2, f32) {
+        let pushed = pos + self.push;
+        let pos = if map.crosses_wall(pos, pushed) {
+            pos
+        } else {
+            pushed
+        };
+        (pos, vel + self.speed_change)
+    }
+}
+
+// karts bounce off each other like two discs. they can only drive forwards, so each keeps the
+// part of its new velocity along where it's facing. none if the karts don't touch
+pub fn bump(a: &PlayerState, b: &PlayerState) -> Option<(Bump, Bump)> {
+    let offset = b.pos - a.pos;
+    let distance = offset.length();
+    if distance >= KART_BUMP_RADIUS * 2.0 {
+        return None;
+    }
+
+    let forward = |rot: f32| Vec2::new(rot.to_radians().cos(), rot.to_radians().sin());
+    let (forward_a, forward_b) = (forward(a.rot), forward(b.rot));
+    // karts right on top of each other are pushed apart along where the first one is facing
+    let normal = if distance > 0.0 {
+        offset / distance
+    } else {
+        forward_a
+    };
+
+    let (mass_a, mass_b) = (KART_MASS, KART_MASS);
+    let velocity_a = forward_a * a.vel;
+    let velocity_b = forward_b * b.vel;
+    // karts already moving apart only get pushed
+    let closing = (velocity_a - velocity_b).dot(normal).max(0.0);
+    let impulse = (1.0 + BUMP_RESTITUTION) * closing / (1.0 / mass_a + 1.0 / mass_b);
+    let new_velocity_a = velocity_a - normal * impulse / mass_a;
+    let new_velocity_b = velocity_b + normal * impulse / mass_b;
+
+    let depth = KART_BUMP_RADIUS * 2.0 - distance;
+    Some((
+        Bump {
+            push: -normal * depth * mass_b / (mass_a + mass_b),
+            speed_change: new_velocity_a.dot(forward_a) - a.vel,
+        },
+        Bump {
+            push: normal * depth * mass_a / (mass_a + mass_b),
+            speed_change: new_velocity_b.dot(forward_b) - b.vel,
+        },
+    ))
+}
+
+fn drift_direction(steer: f32) -> Option<DriftState> {
+    if steer < 0.0 {
+        Some(DriftState::Left)
+    } else if steer > 0.0 {
+        Some(DriftState::Right)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Collider, TrackPoint};
+
+    const DT: f32 = 1.0 / 60.0;
+
+    // a square around the given center, in map units
+    fn square(center: Vec2, half_size: Vec2) -> Collider {
+        Collider {
+            shape: vec![
+                center + Vec2::new(-half_size.x, -half_size.y),
+                center + Vec2::new(half_size.x, -half_size.y),
+                center + Vec2::new(half_size.x, half_size.y),
+                center + Vec2::new(-half_size.x, half_size.y),
+            ],
+        }
+    }
+
+    fn test_map(colliders: Vec<Collider>, offroad: Vec<Collider>) -> PhysicsMap {
+        let point = |x| TrackPoint {
+            pos: Vec2::new(x, 0.0),
+            checkpoint_rotation: 90.0,
+            checkpoint_width_left: 100.0,
+            checkpoint_width_right: 100.0,
+        };
+        let mut map = Map::default();
+        map.track.path = vec![point(-10000.0), point(10000.0)];
+        map.colliders = colliders;
+        map.offroad = offroad;
+        PhysicsMap::new(&map)
+    }
+
+    fn full_throttle() -> KartInput {
+        KartInput {
+            throttle: 1.0,
+            place: 1,
+            ..Default::default()
+        }
+    }
+
+    fn drive(kart: KartState, map: &PhysicsMap, secs: f32) -> KartState {
+        let input = full_throttle();
+        (0..(secs / DT) as usize).fold(kart, |kart, _| kart.step(&input, map, DT))
+    }
+
+    fn player(pos: Vec2, rot: f32, vel: f32) -> PlayerState {
+        PlayerState {
+            pos,
+            rot,
+            vel,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accelerates_towards_move_accel() {
+        let map = test_map(Vec::new(), Vec::new());
+        let target = MOVE_ACCEL + POS_BOOST;
+
+        let mut kart = KartState::new(Vec2::ZERO, 0.0);
+        let mut last_speed = 0.0;
+        for _ in 0..(10.0 / DT) as usize {
+            kart = kart.step(&full_throttle(), &map, DT);
+            assert!(kart.velocity.y >= last_speed);
+            assert!(kart.velocity.y <= target);
+            last_speed = kart.velocity.y;
+        }
+        assert!((kart.velocity.y - target).abs() < 0.01);
+        assert!(kart.pos.x > 0.0 && kart.pos.y.abs() < 0.001);
+    }
+
+    #[test]
+    fn offroad_slows_down() {
+        let offroad = square(Vec2::ZERO, Vec2::splat(1000.0));
+        let map = test_map(Vec::new(), vec![offroad]);
+        let mut kart = KartState::new(Vec2::ZERO, 0.0);
+        kart.velocity.y = MOVE_ACCEL;
+
+        let kart = drive(kart, &map, 3.0);
+        assert!(kart.is_offroad());
+        assert!(kart.velocity.y < MOVE_ACCEL * 0.75);
+        assert!(map.is_offroad(kart.pos));
+    }
+
+    #[test]
+    fn wall_stops_kart() {
+        // the wall starts at x = 50, the kart touches it from x = 45
+        let wall = square(Vec2::new(60.0, 0.0), Vec2::new(10.0, 500.0));
+        let map = test_map(vec![wall], Vec::new());
+        let start = map_coord_to_world(Vec2::new(44.0, 0.0));
+        let mut kart = KartState::new(start, 0.0);
+        kart.velocity.y = MOVE_ACCEL;
+
+        let next = kart.step(&full_throttle(), &map, DT);
+        assert_eq!(next.velocity.y, 0.0);
+        assert!(world_coord_to_map(next.pos).x <= 50.0 - KART_COLLIDER_RADIUS + 0.01);
+        assert!(!map.crosses_wall(kart.pos, next.pos));
+
+        let kart = drive(next, &map, 2.0);
+        assert!(world_coord_to_map(kart.pos).x <= 50.0 - KART_COLLIDER_RADIUS + 0.01);
+    }
+
+    #[test]
+    fn bump_is_symmetric() {
+        let a = player(Vec2::ZERO, 0.0, 10.0);
+        let b = player(Vec2::new(0.5, 0.1), 170.0, 6.0);
+
+        let (a_bump, b_bump) = bump(&a, &b).unwrap();
+        let (b_swapped, a_swapped) = bump(&b, &a).unwrap();
+        for (bump, swapped) in [(a_bump, a_swapped), (b_bump, b_swapped)] {
+            assert!(bump.push.distance(swapped.push) < 1e-5);
+            assert!((bump.speed_change - swapped.speed_change).abs() < 1e-4);
+        }
+
+        // the karts end up just touching, and both slow down after driving into each other
+        let a_pos = a.pos + a_bump.push;
+        let b_pos = b.pos + b_bump.push;
+        assert!((a_pos.distance(b_pos) - KART_BUMP_RADIUS * 2.0).abs() < 1e-5);
+        assert!(a_bump.speed_change < 0.0);
+        assert!(b_bump.speed_change < 0.0);
+    }
+
+    #[test]
+    fn no_bump_when_apart() {
+        let a = player(Vec2::ZERO, 0.0, 10.0);
+        let touching = player(Vec2::new(KART_BUMP_RADIUS * 2.0, 0.0), 180.0, 10.0);
+        let apart = player(Vec2::new(KART_BUMP_RADIUS * 2.0 + 0.01, 0.0), 180.0, 10.0);
+
+        assert!(bump(&a, &touching).is_none());
+        assert!(bump(&a, &apart).is_none());
+        assert!(bump(&a, &player(Vec2::new(KART_BUMP_RADIUS, 0.0), 180.0, 10.0)).is_some());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{ClientId, PROTOCOL_VERSION, ServerMessage};
+use serde::{Deserialize, Serialize};
+
+// a recorded round. playing it back feeds the messages through the same handling as a live race
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    // the protocol version the messages were recorded with. has to stay the first field, see
+    // peek_version
+    pub version: u32,
+    pub map: String,
+    pub players: Vec<(ClientId, String)>,
+    pub events: Vec<ReplayEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayEvent {
+    // seconds since the round started
+    pub time: f32,
+    pub message: ServerMessage,
+}
+
+impl Replay {
+    pub fn new(map: String, players: Vec<(ClientId, String)>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            map,
+            players,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.events.last().map_or(0.0, |e| e.time)
+    }
+
+    // version of a saved replay, even if the rest of it can't be read because it was recorded
+    // on another version. only needs the first few bytes
+    pub fn peek_version(bytes: &[u8]) -> Option<u32> {
+        postcard::take_from_bytes(bytes)
+            .ok()
+            .map(|(version, _)| version)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(bytes)
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+        postcard::to_allocvec(self)
+    }
+}
@@ -1,5 +1,4 @@
-use crate::game::{Collider, Offroad};
-use common::{ClientMessage, map::Map, types::*};
+use common::{ClientMessage, map::Map, physics::PhysicsMap, types::*};
 use include_dir::{Dir, include_dir};
 
 static ASSETS: Dir = include_dir!("$CARGO_MANIFEST_DIR/../assets");
@@ -85,9 +84,7 @@ pub struct UpdateContext<'a> {
     pub rng: &'a mut rand::rngs::SmallRng,
 
     pub map: &'a Map,
-
-    pub colliders: &'a [Collider],
-    pub offroad: &'a [Offroad],
+    pub physics: &'a PhysicsMap,
 }
 
 impl UpdateContext<'_> {
@@ -11,6 +11,7 @@ use crate::engine::{
 // use std::{collections::HashMap, rc::Rc};
 
 use common::types::*;
+use image::DynamicImage;
 
 // const CHAR_SET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789.,!?";
 
@@ -148,6 +149,15 @@ impl UiSprite {
         Self::load_inner(ctx, name, sheet, pos)
     }
 
+    // for images that aren't part of the bundled assets, like map thumbnails
+    pub fn from_image(ctx: &CreateContext, name: &str, img: &DynamicImage, pos: UiVec) -> Self {
+        let sheet = ctx
+            .assets
+            .load_sheet(name, || SpriteSheet::from_images(ctx, &[img]));
+
+        Self::load_inner(ctx, name, sheet, pos)
+    }
+
     fn load_inner(ctx: &CreateContext, name: &str, sheet: SheetRef, pos: UiVec) -> Self {
         let mesh = ctx
             .assets
@@ -205,6 +215,11 @@ impl UiSprite {
         self.width(ctx) * self.aspect
     }
 
+    // height divided by width
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
     pub fn render(&self, ctx: &RenderContext) {
         let sprite_dim = self.sheet.get().sprite_dimensions();
         let sprite_dim = Vec2::new(sprite_dim.x as f32, sprite_dim.y as f32);
@@ -9,16 +9,39 @@ use crate::engine::{
     object::Object,
     sprite::{Billboard, BillboardMode},
 };
-use common::{ClientId, ClientMessage, PickupKind, Placement, ServerMessage, map::Map, types::*};
+use common::{
+    ClientId, ClientMessage, NameRejection, PickupKind, Placement, RoomRequest, ServerMessage,
+    SpectateParams, delta::RaceView, map::Map, physics::PhysicsMap, types::*,
+};
+
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
 
 mod map;
-pub use map::{Collider, Offroad};
 use map::{MapDownload, MapToScene};
 pub mod objects;
 
+mod announcement;
+use announcement::Announcement;
+
 mod assets;
 use assets::SharedAssets;
 
+mod name_input;
+
+mod outdated;
+
+mod replay;
+use replay::{Playback, ReplayPlayer};
+
+mod results;
+use results::ResultsOverlay;
+
+mod spectator;
+use spectator::Spectator;
+
+mod vote;
+use vote::MapVote;
+
 #[derive(Debug)]
 enum State {
     MainMenu {
@@ -26,8 +49,13 @@ enum State {
         state: MainMenuState,
     },
     WaitingToJoin,
+    Voting {
+        vote: MapVote,
+    },
     Loading {
         map_download: MapDownload,
+        // the map is loaded to watch a race that is already running
+        spectate: Option<SpectateParams>,
     },
     WaitingToStart {
         map: Rc<Map>,
@@ -36,6 +64,7 @@ enum State {
         scene: Scene,
         map: Rc<Map>,
         race_state: RaceState,
+        spectator: Option<Spectator>,
     },
 }
 
@@ -49,24 +78,36 @@ enum MainMenuState {
 #[derive(Debug)]
 enum RaceState {
     Waiting,
-    Countdown { current: u32, next: f32 },
-    Running { race_time: f32 },
-    Completed { place: usize },
-    RaceResults { placements: Vec<Placement> },
+    Countdown {
+        current: u32,
+        next: f32,
+    },
+    Running {
+        race_time: f32,
+    },
+    Completed {
+        place: usize,
+    },
+    RaceResults {
+        placements: Vec<Placement>,
+        overlay: ResultsOverlay,
+    },
 }
 
 #[derive(Debug)]
 struct Scene {
     own_id: ClientId,
-    player: objects::Player,
+    // spectators don't drive themselves
+    player: Option<objects::Player>,
     players: HashMap<ClientId, objects::ExternalPlayer>,
 
-    colliders: Vec<Collider>,
-    offroad: Vec<Offroad>,
+    physics: PhysicsMap,
 
     coins: Vec<objects::Coin>,
     item_boxes: Vec<objects::ItemBox>,
     items: Vec<objects::Item>,
+    // what the server has told us about the race so far
+    race_view: RaceView,
     explosions: Vec<Billboard>,
 
     map: objects::Map,
@@ -74,11 +115,22 @@ struct Scene {
     static_objects: Vec<Box<dyn Object>>,
 }
 
+impl Scene {
+    // the coin count is the server's, picking up a coin only shows once it agrees
+    fn update_coins(&mut self, coins: &[(ClientId, u32)]) {
+        let own = coins.iter().find(|(id, _)| *id == self.own_id);
+        if let (Some(player), Some((_, count))) = (&mut self.player, own) {
+            player.coins = *count;
+        }
+    }
+}
+
 impl std::fmt::Display for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             State::MainMenu { .. } => write!(f, "MainMenu"),
             State::WaitingToJoin => write!(f, "WaitingToJoin"),
+            State::Voting { .. } => write!(f, "Voting"),
             State::Loading { .. } => write!(f, "Loading"),
             State::WaitingToStart { .. } => write!(f, "WaitingToStart"),
             State::Running { .. } => write!(f, "Running"),
@@ -88,8 +140,16 @@ impl std::fmt::Display for State {
 
 pub struct Game {
     ws: WebSocket,
+    ws_tx: mpsc::Sender<ServerMessage>,
     ws_rx: mpsc::Receiver<ServerMessage>,
 
+    session_token: Option<String>,
+    resume_pending: bool,
+    // joining again once the socket that replaces an expired session is open
+    register_pending: bool,
+    reconnect_attempts: u32,
+    reconnect_timer: f32,
+
     mouse_pos: Vec2,
     hide_cursor: bool,
 
@@ -100,6 +160,8 @@ pub struct Game {
     ui_cam: UiCamera,
 
     player_count: usize,
+    replay: Option<ReplayPlayer>,
+    announcement: Option<Announcement>,
 
     rng: rand::rngs::SmallRng,
 
@@ -113,6 +175,7 @@ pub struct Game {
 impl Game {
     pub fn new(
         ws: WebSocket,
+        ws_tx: mpsc::Sender<ServerMessage>,
         ws_rx: mpsc::Receiver<ServerMessage>,
         gl: glow::Context,
         viewport: Vec2,
@@ -138,6 +201,8 @@ impl Game {
         let time = web_sys::window().unwrap().performance().unwrap().now();
         let rng = rand::rngs::SmallRng::seed_from_u64((time * 12345.0) as u64);
 
+        name_input::show();
+
         let ctx = CreateContext {
             gl: &gl,
             assets: &cache,
@@ -147,9 +212,16 @@ impl Game {
 
         Self {
             ws,
+            ws_tx,
             ws_rx,
             gl,
 
+            session_token: None,
+            resume_pending: false,
+            register_pending: false,
+            reconnect_attempts: 0,
+            reconnect_timer: 0.0,
+
             mouse_pos: Vec2::default(),
             hide_cursor: false,
 
@@ -159,6 +231,8 @@ impl Game {
             cam: Camera::new(60.0, viewport),
 
             player_count: 1,
+            replay: None,
+            announcement: None,
 
             rng,
 
@@ -175,8 +249,19 @@ impl Game {
     }
 
     pub fn connect(&mut self) {
+        if let Some(replay) = requested_replay() {
+            log::info!("playing replay '{}'", replay);
+            self.replay = Some(ReplayPlayer::load(format!("replays/{}", replay)));
+            self.state = State::WaitingToJoin;
+            return;
+        }
+
+        name_input::hide();
         self.send(ClientMessage::Register {
-            name: "cool player".to_string(),
+            version: common::PROTOCOL_VERSION,
+            name: name_input::name(),
+            room: requested_room(),
+            spectate: requested_spectate(),
         });
         self.state = State::WaitingToJoin;
     }
@@ -198,233 +283,621 @@ impl Game {
             State::MainMenu { click, .. } => {
                 *click = true;
             }
+            State::Voting { vote } => vote.click = true,
             _ => {}
         }
     }
     pub fn mouse_up(&mut self) {}
 
     pub fn key_down(&mut self, key: String) {
+        self.hide_cursor = true;
+        if self
+            .replay
+            .as_mut()
+            .is_some_and(|replay| replay.key_down(&key))
+        {
+            return;
+        }
+
         match &mut self.state {
+            State::Running {
+                scene,
+                spectator: Some(spectator),
+                ..
+            } => {
+                spectator.key_down(&key, &scene.players);
+            }
             State::Running {
                 scene,
                 race_state: RaceState::Running { .. },
                 ..
             } => {
-                scene.player.key_down(&key, self.swap_controls);
+                if let Some(player) = &mut scene.player {
+                    player.key_down(&key, self.swap_controls);
+                }
+            }
+            State::Voting { vote } => {
+                if let Some(candidate) = vote.key_down(&key) {
+                    self.send(ClientMessage::VoteMap(candidate));
+                }
             }
             _ => {}
         }
-        self.hide_cursor = true;
     }
     pub fn key_up(&mut self, key: String) {
         match &mut self.state {
+            State::Running {
+                spectator: Some(spectator),
+                ..
+            } => {
+                spectator.key_up(&key);
+            }
             State::Running {
                 scene,
                 race_state: RaceState::Running { .. },
                 ..
             } => {
-                scene.player.key_up(&key, self.swap_controls);
+                if let Some(player) = &mut scene.player {
+                    player.key_up(&key, self.swap_controls);
+                }
             }
             _ => {}
         }
     }
 
     fn send(&self, msg: ClientMessage) {
-        let bytes = msg.to_bytes().unwrap();
-        match self.ws.send_with_u8_array(&bytes) {
-            Ok(_) => {}
-            Err(err) => log::error!("Error sending message: {:?}", err),
-        }
+        send_to_socket(&self.ws, msg);
     }
 
-    pub fn update(&mut self, dt: f32, tick: bool) {
-        let dt = dt.min(0.1); // cap to 100ms
-
-        // handle messages
-        while let Ok(msg) = self.ws_rx.try_recv() {
-            match (msg, &mut self.state) {
-                (ServerMessage::DuplicateLogin, _) => {
+    // reopen the socket with an increasing delay and resume the session once it is open again
+    fn check_connection(&mut self, dt: f32) {
+        if self.register_pending {
+            match self.ws.ready_state() {
+                WebSocket::OPEN => {
+                    self.register_pending = false;
+                    self.connect();
+                }
+                WebSocket::CLOSED => {
+                    self.register_pending = false;
                     crate::alert(
-                        "youve already joined the game under this ip address. make sure you dont have another browser tab open with the game running.\nplease refresh the page to try again.",
+                        "lost connection to the server.\nplease refresh the page to try again.",
                     );
                 }
+                _ => {}
+            }
+            return;
+        }
 
-                (ServerMessage::PrepareRound { map }, _) => {
-                    log::info!("preparing round with map: {:?}", map);
-                    let map_download = MapDownload::start(map);
-                    self.state = State::Loading { map_download };
+        if self.session_token.is_none() {
+            return;
+        }
+
+        match self.ws.ready_state() {
+            WebSocket::OPEN if self.resume_pending => {
+                self.resume_pending = false;
+                let token = self.session_token.clone().unwrap();
+                self.send(ClientMessage::Resume {
+                    version: common::PROTOCOL_VERSION,
+                    token,
+                });
+            }
+            WebSocket::CLOSED => {
+                self.reconnect_timer -= dt;
+                if self.reconnect_timer > 0.0 {
+                    return;
                 }
 
-                (ServerMessage::LoadedTooSlow, _) => {
-                    log::warn!("loaded too slow");
-                    self.state = State::WaitingToJoin;
+                if self.reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+                    self.session_token = None;
+                    crate::alert(
+                        "lost connection to the server.\nplease refresh the page to try again.",
+                    );
+                    return;
                 }
 
-                (ServerMessage::StartRound { params }, State::WaitingToStart { map, .. }) => {
-                    log::info!("starting round with params: {:?}", params);
-
-                    self.cache.clear();
-                    let ctx = CreateContext {
-                        gl: &self.gl,
-                        assets: &self.cache,
-                        viewport: self.viewport,
-                    };
-                    objects::Item::preload_assets(&ctx);
-                    let scene = map.to_scene(&ctx, &params);
-
-                    self.state = State::Running {
-                        map: map.clone(),
-                        scene,
-                        race_state: RaceState::Waiting,
-                    };
+                self.reconnect_attempts += 1;
+                self.reconnect_timer = 2.0_f32.powi(self.reconnect_attempts as i32).min(8.0);
+                log::warn!(
+                    "connection lost, reconnecting (attempt {})",
+                    self.reconnect_attempts
+                );
+
+                self.ws = crate::open_socket(self.ws_tx.clone());
+                self.resume_pending = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn update_replay(&mut self, dt: f32) {
+        let Some(replay) = &mut self.replay else {
+            return;
+        };
+
+        let messages = match replay.update(dt) {
+            Playback::Waiting => return,
+            Playback::Restart {
+                map,
+                params,
+                messages,
+            } => {
+                // keep looking at the same racer after seeking
+                let spectator = match &mut self.state {
+                    State::Running { spectator, .. } => spectator.take(),
+                    _ => None,
+                };
+                self.spectate(map, params);
+                if let (Some(old), State::Running { spectator, .. }) = (spectator, &mut self.state)
+                {
+                    *spectator = Some(old);
                 }
-                (ServerMessage::StartRound { .. }, _) => {
-                    log::warn!("received StartRound message in invalid state");
+                messages
+            }
+            Playback::Messages(messages) => messages,
+        };
+
+        for msg in messages {
+            self.handle_message(msg);
+        }
+    }
+
+    // watch a race without taking part in it
+    fn spectate(&mut self, map: Rc<Map>, params: SpectateParams) {
+        self.cache.clear();
+        let ctx = CreateContext {
+            gl: &self.gl,
+            assets: &self.cache,
+            viewport: self.viewport,
+        };
+        objects::Item::preload_assets(&ctx);
+        let mut scene = map.to_spectator_scene(&ctx, &params.players);
+        for (coin, state) in scene.coins.iter_mut().zip(params.coin_states) {
+            coin.state = state;
+        }
+        for (item_box, state) in scene.item_boxes.iter_mut().zip(params.item_box_states) {
+            item_box.state = state;
+        }
+
+        let spectator = Spectator::new(scene.map.dimensions());
+        let race_state = match params.race_time {
+            Some(race_time) => RaceState::Running { race_time },
+            None => RaceState::Waiting,
+        };
+        self.state = State::Running {
+            scene,
+            map,
+            race_state,
+            spectator: Some(spectator),
+        };
+    }
+
+    fn handle_message(&mut self, msg: ServerMessage) {
+        match (msg, &mut self.state) {
+            (ServerMessage::OutdatedClient { server_version }, _) => {
+                log::warn!(
+                    "server is on protocol version {}, we are on {}",
+                    server_version,
+                    common::PROTOCOL_VERSION
+                );
+                // resuming would only get the same answer
+                self.session_token = None;
+                name_input::hide();
+                outdated::show();
+            }
+
+            (ServerMessage::TooManyConnections, _) => {
+                crate::alert(
+                    "there are too many connections to the game from your address. close any other browser tabs running the game.\nplease refresh the page to try again.",
+                );
+            }
+
+            (ServerMessage::JoinedRoom { code, token }, _) => {
+                self.session_token = Some(token);
+                if let Some(code) = code {
+                    log::info!("joined private room {}", code);
+                    set_room_url(Some(&code));
                 }
+            }
+            (ServerMessage::SessionExpired, _) => {
+                log::warn!("session expired, joining again");
+                self.session_token = None;
+                // the server closes the connection after telling us
+                self.ws = crate::open_socket(self.ws_tx.clone());
+                self.register_pending = true;
+                self.state = State::WaitingToJoin;
+            }
+            (ServerMessage::Resync { race }, state) => {
+                log::info!("resumed session");
+                self.reconnect_attempts = 0;
+
+                match (race, state) {
+                    (
+                        Some(race),
+                        State::Running {
+                            scene,
+                            race_state,
+                            spectator: None,
+                            ..
+                        },
+                    ) => {
+                        if let Some(player) = &mut scene.player {
+                            player.resync(race.state, race.item, race.coins);
+                        }
+                        for (coin, state) in scene.coins.iter_mut().zip(race.coin_states) {
+                            coin.state = state;
+                        }
+                        for (item_box, state) in
+                            scene.item_boxes.iter_mut().zip(race.item_box_states)
+                        {
+                            item_box.state = state;
+                        }
 
-                (ServerMessage::StartCountdown, State::Running { race_state, .. }) => {
-                    *race_state = RaceState::Countdown {
-                        current: 3,
-                        next: 0.0,
-                    };
+                        // the player might have crossed the line while the connection was down
+                        if let Some(race_time) = race.race_time {
+                            if !race.finished {
+                                *race_state = RaceState::Running { race_time };
+                            }
+                        }
+                    }
+                    // the round ended while the connection was down. spectators are sent
+                    // the current round again right after this
+                    (None, State::Running { .. }) => self.state = State::WaitingToJoin,
+                    _ => {}
                 }
-                (ServerMessage::StartCountdown, _) => {
-                    log::warn!("received StartCountdown message in invalid state");
+            }
+
+            (ServerMessage::RoomNotFound, _) => {
+                set_room_url(None);
+                crate::alert(
+                    "this room doesnt exist anymore. ask your friends for a new link or refresh the page to join a public game.",
+                );
+            }
+            (ServerMessage::RoomFull, _) => {
+                set_room_url(None);
+                crate::alert(
+                    "this room is already full.\nplease refresh the page to join a public game instead.",
+                );
+            }
+            (ServerMessage::NameAccepted(name), _) => {
+                log::info!("playing as '{}'", name);
+            }
+            (ServerMessage::NameRejected(reason), _) => {
+                let reason = match reason {
+                    NameRejection::TooShort { min } => {
+                        format!("it needs to be at least {} characters long", min)
+                    }
+                    NameRejection::TooLong { max } => {
+                        format!("it can be at most {} characters long", max)
+                    }
+                    NameRejection::NotAllowed => "it isnt allowed on this server".to_string(),
+                };
+                crate::alert(&format!("please pick another name, {}.", reason));
+
+                // the server closes the connection, so open a new one for the next try
+                self.ws = crate::open_socket(self.ws_tx.clone());
+                self.state = State::MainMenu {
+                    click: false,
+                    state: MainMenuState::Main,
+                };
+                name_input::show();
+            }
+            (ServerMessage::Kicked, _) => {
+                // dont try to resume the session once the server closes the connection
+                self.session_token = None;
+                crate::alert("youve been kicked from the server.");
+            }
+            (ServerMessage::Announcement(message), _) => {
+                log::info!("announcement: {}", message);
+                // the old announcement hides the overlay when it is dropped
+                self.announcement = None;
+                self.announcement = Some(Announcement::show(&message));
+            }
+
+            (
+                ServerMessage::MapVote {
+                    candidates,
+                    duration,
+                },
+                _,
+            ) => {
+                log::info!(
+                    "voting for the next map for {}s: {:?}",
+                    duration,
+                    candidates
+                );
+                self.state = State::Voting {
+                    vote: MapVote::new(candidates, duration),
+                };
+            }
+
+            (ServerMessage::PrepareRound { map }, _) => {
+                log::info!("preparing round with map: {:?}", map);
+                let map_download = MapDownload::start(map);
+                self.state = State::Loading {
+                    map_download,
+                    spectate: None,
+                };
+            }
+
+            (ServerMessage::Spectate { params }, _) => {
+                log::info!("spectating round on map: {:?}", params.map);
+                let map_download = MapDownload::start(params.map.clone());
+                self.state = State::Loading {
+                    map_download,
+                    spectate: Some(params),
+                };
+            }
+
+            (ServerMessage::LoadedTooSlow, _) => {
+                log::warn!("loaded too slow");
+                self.state = State::WaitingToJoin;
+            }
+
+            (ServerMessage::StartRound { params }, State::WaitingToStart { map, .. }) => {
+                log::info!("starting round with params: {:?}", params);
+
+                self.cache.clear();
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
+                objects::Item::preload_assets(&ctx);
+                let scene = map.to_scene(&ctx, &params);
+
+                self.state = State::Running {
+                    map: map.clone(),
+                    scene,
+                    race_state: RaceState::Waiting,
+                    spectator: None,
+                };
+            }
+            (ServerMessage::StartRound { .. }, _) => {
+                log::warn!("received StartRound message in invalid state");
+            }
+
+            (ServerMessage::StartCountdown, State::Running { race_state, .. }) => {
+                *race_state = RaceState::Countdown {
+                    current: 3,
+                    next: 0.0,
+                };
+            }
+            (ServerMessage::StartCountdown, _) => {
+                log::warn!("received StartCountdown message in invalid state");
+            }
+
+            (ServerMessage::StartRace, State::Running { race_state, .. }) => {
+                *race_state = RaceState::Running { race_time: 0.0 }
+            }
+            (ServerMessage::StartRace, _) => {
+                log::warn!("received StartRace message in invalid state");
+            }
+
+            (
+                ServerMessage::RaceUpdate {
+                    players,
+                    coins,
+                    active_items,
+                    race_time: new_race_time,
+                },
+                State::Running {
+                    scene, race_state, ..
+                },
+            ) => {
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
+
+                if let RaceState::Running { race_time } = race_state {
+                    *race_time = new_race_time;
                 }
 
-                (ServerMessage::StartRace, State::Running { race_state, .. }) => {
-                    *race_state = RaceState::Running { race_time: 0.0 }
+                scene.items.clear();
+                scene.items.extend(
+                    active_items
+                        .into_iter()
+                        .map(|i| objects::Item::new(&ctx, i)),
+                );
+
+                for (id, state) in players {
+                    if let Some(player) = scene.players.get_mut(&id) {
+                        player.update_state(new_race_time, state);
+                    }
                 }
-                (ServerMessage::StartRace, _) => {
-                    log::warn!("received StartRace message in invalid state");
+                scene.update_coins(&coins);
+            }
+
+            (ServerMessage::RaceUpdate { .. }, _) => {
+                log::warn!("received RaceUpdate message in invalid state");
+            }
+
+            (
+                ServerMessage::RaceDelta {
+                    race_time: new_race_time,
+                    keyframe,
+                    players,
+                    coins,
+                    spawned_items,
+                    moved_items,
+                    despawned_items,
+                },
+                State::Running {
+                    scene, race_state, ..
+                },
+            ) => {
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
+
+                if let RaceState::Running { race_time } = race_state {
+                    *race_time = new_race_time;
                 }
 
-                (
-                    ServerMessage::RaceUpdate {
-                        players,
-                        active_items,
-                        race_time: new_race_time,
-                    },
-                    State::Running {
-                        scene, race_state, ..
-                    },
-                ) => {
-                    let ctx = CreateContext {
-                        gl: &self.gl,
-                        assets: &self.cache,
-                        viewport: self.viewport,
-                    };
-
-                    if let RaceState::Running { race_time } = race_state {
-                        *race_time = new_race_time;
-                    }
+                let players = scene.race_view.apply(
+                    keyframe,
+                    players,
+                    spawned_items,
+                    moved_items,
+                    despawned_items,
+                );
 
-                    scene.items.clear();
-                    scene.items.extend(
-                        active_items
-                            .into_iter()
-                            .map(|i| objects::Item::new(&ctx, i)),
-                    );
+                scene.items.clear();
+                scene
+                    .items
+                    .extend(scene.race_view.items().map(|i| objects::Item::new(&ctx, i)));
 
-                    for (id, state) in players {
-                        if let Some(player) = scene.players.get_mut(&id) {
-                            player.update_state(state);
-                        }
+                for (id, state) in players {
+                    if let Some(player) = scene.players.get_mut(&id) {
+                        player.update_state(new_race_time, state);
                     }
                 }
+                scene.update_coins(&coins);
+            }
 
-                (ServerMessage::RaceUpdate { .. }, _) => {
-                    log::warn!("received RaceUpdate message in invalid state");
-                }
+            (ServerMessage::RaceDelta { .. }, _) => {
+                log::warn!("received RaceDelta message in invalid state");
+            }
 
-                (
-                    ServerMessage::PickUpStateChange { kind, index, state },
-                    State::Running { scene, .. },
-                ) => match kind {
-                    PickupKind::Coin => {
-                        let coins = &mut scene.coins;
-                        if let Some(coin) = coins.get_mut(index) {
-                            coin.state = state;
-                        }
+            (ServerMessage::Correction(state), State::Running { scene, .. }) => {
+                log::warn!("server corrected our position");
+                if let Some(player) = &mut scene.player {
+                    player.correct(state);
+                }
+            }
+            (ServerMessage::Correction(_), _) => {}
+
+            (
+                ServerMessage::PickUpStateChange { kind, index, state },
+                State::Running { scene, .. },
+            ) => match kind {
+                PickupKind::Coin => {
+                    let coins = &mut scene.coins;
+                    if let Some(coin) = coins.get_mut(index) {
+                        coin.state = state;
                     }
-                    PickupKind::ItemBox => {
-                        let item_boxes = &mut scene.item_boxes;
-                        if let Some(item_box) = item_boxes.get_mut(index) {
-                            item_box.state = state;
-                        }
+                }
+                PickupKind::ItemBox => {
+                    let item_boxes = &mut scene.item_boxes;
+                    if let Some(item_box) = item_boxes.get_mut(index) {
+                        item_box.state = state;
                     }
-                },
-                (ServerMessage::PickUpStateChange { .. }, _) => {
-                    log::warn!("received PickUpStateChange message in invalid state");
                 }
+            },
+            (ServerMessage::PickUpStateChange { .. }, _) => {
+                log::warn!("received PickUpStateChange message in invalid state");
+            }
 
-                // (
-                //     ServerMessage::PlayerCollision {
-                //         normal,
-                //         depth,
-                //
-                //         other_velocity,
-                //         other_rotation,
-                //     },
-                //     State::Running {
-                //         scene,
-                //         race_state: RaceState::Running { .. },
-                //         ..
-                //     },
-                // ) => {
-                //     scene
-                //         .player
-                //         .apply_collision(normal, depth, other_velocity, other_rotation);
-                // }
-                // (ServerMessage::PlayerCollision { .. }, _) => {
-                //     log::warn!("received PlayerCollision message in invalid state");
-                // }
-                (ServerMessage::HitByItem { player }, State::Running { scene, .. }) => {
-                    let ctx = CreateContext {
-                        gl: &self.gl,
-                        assets: &self.cache,
-                        viewport: self.viewport,
-                    };
-
-                    let player: Option<&dyn Object> = if player == scene.own_id {
-                        scene.player.hit();
-
-                        Some(&scene.player)
-                    } else if let Some(player) = scene.players.get(&player) {
-                        Some(player)
-                    } else {
-                        None
-                    };
-
-                    if let Some(player) = player {
-                        let to_camera = player.as_ref().pos - self.cam.transform.pos;
-
-                        let mut billboard =
-                            Billboard::new(&ctx, "explosion", self.shared_assets.explosion.clone());
-                        billboard.transform.pos =
-                            player.as_ref().pos + to_camera.normalize() * -0.05;
-                        scene.explosions.push(billboard);
-                    }
+            (ServerMessage::ReceivedItem { item }, State::Running { scene, .. }) => {
+                if let Some(player) = &mut scene.player {
+                    player.item = Some(item);
                 }
-                (ServerMessage::HitByItem { .. }, _) => {
-                    log::warn!("received HitByItem message in invalid state");
+            }
+            (ServerMessage::ReceivedItem { .. }, _) => {
+                log::warn!("received ReceivedItem message in invalid state");
+            }
+
+            (ServerMessage::Bumped(bump), State::Running { scene, .. }) => {
+                if let Some(player) = &mut scene.player {
+                    player.bump(&bump, &scene.physics);
                 }
+            }
+            (ServerMessage::Bumped(_), _) => {}
+
+            (ServerMessage::HitByItem { player }, State::Running { scene, .. }) => {
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
 
-                (ServerMessage::PlayerCountChanged { count }, _) => self.player_count = count,
-                (ServerMessage::PlayerLeft(id), _) => {
-                    if let State::Running { scene, .. } = &mut self.state {
-                        scene.players.remove(&id);
+                let player: Option<&dyn Object> = match &mut scene.player {
+                    Some(own) if player == scene.own_id => {
+                        own.hit();
+
+                        Some(own)
                     }
-                }
+                    _ => scene.players.get(&player).map(|p| p as &dyn Object),
+                };
 
-                (ServerMessage::EndRound { placements }, State::Running { race_state, .. }) => {
-                    *race_state = RaceState::RaceResults { placements };
+                if let Some(player) = player {
+                    scene.explosions.push(explosion(
+                        &ctx,
+                        &self.shared_assets,
+                        &self.cam,
+                        player.as_ref().pos,
+                    ));
                 }
-                (ServerMessage::EndRound { .. }, _) => {
-                    self.state = State::WaitingToJoin;
+            }
+            (ServerMessage::HitByItem { .. }, _) => {
+                log::warn!("received HitByItem message in invalid state");
+            }
+
+            (ServerMessage::ItemsCollided { pos }, State::Running { scene, .. }) => {
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
+
+                // at the height items are drawn at
+                let pos = Vec3::new(pos.x, -0.2, pos.y);
+                scene
+                    .explosions
+                    .push(explosion(&ctx, &self.shared_assets, &self.cam, pos));
+            }
+            (ServerMessage::ItemsCollided { .. }, _) => {
+                log::warn!("received ItemsCollided message in invalid state");
+            }
+
+            (ServerMessage::PlayerCountChanged { count }, _) => self.player_count = count,
+            (ServerMessage::PlayerLeft(id), _) => {
+                if let State::Running { scene, .. } = &mut self.state {
+                    scene.players.remove(&id);
                 }
             }
+
+            (
+                ServerMessage::EndRound { placements, record },
+                State::Running {
+                    scene, race_state, ..
+                },
+            ) => {
+                let overlay = ResultsOverlay::show(&placements, record.as_ref(), |id| {
+                    if id == scene.own_id {
+                        return "you".to_string();
+                    }
+                    scene
+                        .players
+                        .get(&id)
+                        .map_or_else(|| "?".to_string(), |p| p.name().to_string())
+                });
+                *race_state = RaceState::RaceResults {
+                    placements,
+                    overlay,
+                };
+            }
+            (ServerMessage::EndRound { .. }, _) => {
+                self.state = State::WaitingToJoin;
+            }
         }
+    }
+
+    pub fn update(&mut self, dt: f32, tick: bool) {
+        let dt = dt.min(0.1); // cap to 100ms
+
+        self.check_connection(dt);
+
+        // handle messages
+        while let Ok(msg) = self.ws_rx.try_recv() {
+            self.handle_message(msg);
+        }
+        self.update_replay(dt);
+        self.announcement
+            .take_if(|announcement| announcement.expired(dt));
 
         // update
         match &mut self.state {
@@ -432,23 +905,16 @@ impl Game {
                 scene,
                 map,
                 race_state,
+                spectator,
             } => {
                 let mut ctx = UpdateContext {
                     dt,
                     tick,
                     assets: &self.cache,
-                    send_msg: &mut |msg| {
-                        let bytes = msg.to_bytes().unwrap();
-                        match self.ws.send_with_u8_array(&bytes) {
-                            Ok(_) => {}
-                            Err(err) => log::error!("Error sending message: {:?}", err),
-                        }
-                    },
+                    send_msg: &mut |msg| send_to_socket(&self.ws, msg),
 
                     map: &map,
-
-                    colliders: &scene.colliders,
-                    offroad: &scene.offroad,
+                    physics: &scene.physics,
 
                     rng: &mut self.rng,
                 };
@@ -464,14 +930,19 @@ impl Game {
                 scene.coins.iter_mut().for_each(|c| c.update(&mut ctx));
                 scene.item_boxes.iter_mut().for_each(|i| i.update(&mut ctx));
 
-                scene.player.update(&mut ctx);
-                scene.player.late_update(
-                    &mut ctx,
-                    &scene.players,
-                    &mut scene.coins,
-                    &mut scene.item_boxes,
-                    &mut self.cam,
-                );
+                if let Some(player) = &mut scene.player {
+                    player.update(&mut ctx);
+                    player.late_update(
+                        &mut ctx,
+                        &scene.players,
+                        &mut scene.coins,
+                        &mut scene.item_boxes,
+                        &mut self.cam,
+                    );
+                }
+                if let Some(spectator) = spectator {
+                    spectator.update(dt, &scene.players, &mut self.cam);
+                }
 
                 if ctx.tick {
                     let explosion_frames = self.shared_assets.explosion.get().sprite_amount();
@@ -497,13 +968,17 @@ impl Game {
                         }
                     }
                     RaceState::Running { race_time } => {
-                        if scene.player.track_pos.lap > 3 {
+                        if let Some(player) = scene
+                            .player
+                            .as_mut()
+                            .filter(|p| p.kart.track_pos.lap > common::LAP_COUNT)
+                        {
                             let race_time = *race_time;
                             *race_state = RaceState::Completed {
-                                place: scene.player.place,
+                                place: player.place,
                             };
-                            scene.player.input = Default::default();
-                            scene.player.drift_state = Default::default();
+                            player.input = Default::default();
+                            player.drift = false;
                             self.send(ClientMessage::FinishRound { race_time });
                         }
                     }
@@ -511,7 +986,10 @@ impl Game {
                     RaceState::RaceResults { .. } => {}
                 }
             }
-            State::Loading { map_download } => {
+            State::Loading {
+                map_download,
+                spectate,
+            } => {
                 let map = match map_download.poll() {
                     Some(Ok(map)) => map,
                     Some(Err(err)) => {
@@ -521,6 +999,12 @@ impl Game {
                     None => return,
                 };
 
+                if let Some(params) = spectate.take() {
+                    log::info!("loaded map '{:?}', spectating", map.metadata.name);
+                    self.spectate(Rc::new(map), params);
+                    return;
+                }
+
                 log::info!(
                     "loaded map '{:?}', waiting for round start",
                     map.metadata.name
@@ -531,6 +1015,16 @@ impl Game {
                 // let cam = Camera::new(60.0, self.viewport);
                 // self.state = State::Running { cam, objects, map };
             }
+            State::Voting { vote } => {
+                let ctx = CreateContext {
+                    gl: &self.gl,
+                    assets: &self.cache,
+                    viewport: self.viewport,
+                };
+                if let Some(candidate) = vote.update(&ctx, self.mouse_pos, dt) {
+                    self.send(ClientMessage::VoteMap(candidate));
+                }
+            }
             State::MainMenu { click, state } => {
                 if *click {
                     *click = false;
@@ -542,6 +1036,7 @@ impl Game {
                                 .credits_button
                                 .hovered(self.viewport, self.mouse_pos)
                             {
+                                name_input::hide();
                                 *state = MainMenuState::Credits;
                             } else if self
                                 .shared_assets
@@ -554,6 +1049,7 @@ impl Game {
                                 .settings_button
                                 .hovered(self.viewport, self.mouse_pos)
                             {
+                                name_input::hide();
                                 *state = MainMenuState::Settings;
                             }
                         }
@@ -571,6 +1067,7 @@ impl Game {
                                 .back_button
                                 .hovered(self.viewport, self.mouse_pos)
                             {
+                                name_input::show();
                                 *state = MainMenuState::Main;
                             }
                         }
@@ -581,6 +1078,7 @@ impl Game {
                                 .back_button
                                 .hovered(self.viewport, self.mouse_pos)
                             {
+                                name_input::show();
                                 *state = MainMenuState::Main;
                             }
                         }
@@ -613,14 +1111,17 @@ impl Game {
 
         match &self.state {
             State::Running {
-                scene, race_state, ..
+                scene,
+                race_state,
+                spectator,
+                ..
             } => {
                 let mut depth_objects: Vec<(&dyn Object, f32)> = scene
                     .static_objects
                     .iter()
                     .map(|o| o.as_ref() as &dyn Object)
                     .chain(scene.players.values().map(|o| o as &dyn Object))
-                    .chain(std::iter::once(&scene.player as &dyn Object))
+                    .chain(scene.player.iter().map(|o| o as &dyn Object))
                     .chain(scene.coins.iter().map(|o| o as &dyn Object))
                     .chain(scene.item_boxes.iter().map(|o| o as &dyn Object))
                     .chain(scene.items.iter().map(|o| o as &dyn Object))
@@ -649,20 +1150,29 @@ impl Game {
                             .render_countdown(&ctx, (*current).max(1) as u32);
                     }
                     RaceState::Running { .. } => {
-                        self.shared_assets.item_frame.render(&ctx);
-                        if let Some(item) = scene.player.item {
-                            self.shared_assets.render_item(&ctx, item);
+                        if let Some(player) = &scene.player {
+                            self.shared_assets.item_frame.render(&ctx);
+                            if let Some(item) = player.item {
+                                self.shared_assets.render_item(&ctx, item);
+                            }
+
+                            self.shared_assets
+                                .render_coin_count(&ctx, player.coins as u32);
+                            self.shared_assets.render_pos(&ctx, player.place as u32);
                         }
 
-                        self.shared_assets
-                            .render_coin_count(&ctx, scene.player.coins as u32);
-                        self.shared_assets
-                            .render_pos(&ctx, scene.player.place as u32);
+                        // spectators see the place of the racer they are following
+                        let place = spectator
+                            .as_ref()
+                            .and_then(|s| s.target_place(&scene.players));
+                        if let Some(place) = place {
+                            self.shared_assets.render_pos(&ctx, place as u32);
+                        }
                     }
                     RaceState::Completed { place } => {
                         self.shared_assets.render_pos_centered(&ctx, *place as u32);
                     }
-                    RaceState::RaceResults { placements } => {
+                    RaceState::RaceResults { placements, .. } => {
                         let place = placements
                             .iter()
                             .position(|p| p.client_id == scene.own_id)
@@ -685,6 +1195,13 @@ impl Game {
                 self.shared_assets.join_waiting.render(&ctx);
             }
 
+            State::Voting { vote } => {
+                unsafe { self.gl.disable(glow::DEPTH_TEST) };
+
+                self.shared_assets.render_logo(&ctx);
+                vote.render(&ctx);
+            }
+
             State::WaitingToStart { .. } => {
                 unsafe { self.gl.disable(glow::DEPTH_TEST) };
 
@@ -727,6 +1244,92 @@ impl Game {
     }
 }
 
+// pulled a bit towards the camera, so it shows in front of whatever blew up
+fn explosion(ctx: &CreateContext, assets: &SharedAssets, cam: &Camera, pos: Vec3) -> Billboard {
+    let to_camera = pos - cam.transform.pos;
+    let mut billboard = Billboard::new(ctx, "explosion", assets.explosion.clone());
+    billboard.transform.pos = pos + to_camera.normalize() * -0.05;
+    billboard
+}
+
+// messages sent while the connection is down are dropped, the session is resynced after reconnecting
+fn send_to_socket(ws: &WebSocket, msg: ClientMessage) {
+    if ws.ready_state() != WebSocket::OPEN {
+        return;
+    }
+
+    let bytes = msg.to_bytes().unwrap();
+    match ws.send_with_u8_array(&bytes) {
+        Ok(_) => {}
+        Err(err) => log::error!("Error sending message: {:?}", err),
+    }
+}
+
+// `?room=new` opens a private room, `?room=<code>` joins one
+fn requested_room() -> RoomRequest {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    let room = web_sys::UrlSearchParams::new_with_str(&search)
+        .ok()
+        .and_then(|params| params.get("room"));
+
+    match room.as_deref().map(str::trim) {
+        None | Some("") => RoomRequest::Public,
+        Some("new") => RoomRequest::CreatePrivate,
+        Some(code) => RoomRequest::JoinPrivate(code.to_string()),
+    }
+}
+
+// `?replay=<name>` plays a saved replay instead of joining a game
+fn requested_replay() -> Option<String> {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    web_sys::UrlSearchParams::new_with_str(&search)
+        .ok()
+        .and_then(|params| params.get("replay"))
+        .filter(|replay| !replay.trim().is_empty())
+}
+
+// `?spectate` only watches the races in the room
+fn requested_spectate() -> bool {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    web_sys::UrlSearchParams::new_with_str(&search).is_ok_and(|params| params.has("spectate"))
+}
+
+// keep the address bar pointing at the current room so it can be shared directly
+fn set_room_url(code: Option<&str>) {
+    let window = web_sys::window().unwrap();
+    let mut url = match code {
+        Some(code) => format!("?room={}", code),
+        None => window
+            .location()
+            .pathname()
+            .unwrap_or_else(|_| "/".to_string()),
+    };
+    // keep watching after a refresh
+    if requested_spectate() {
+        url += if code.is_some() {
+            "&spectate"
+        } else {
+            "?spectate"
+        };
+    }
+
+    if let Ok(history) = window.history() {
+        let _ = history.replace_state_with_url(&wasm_bindgen::JsValue::NULL, "", Some(&url));
+    }
+}
+
 impl Drop for Game {
     fn drop(&mut self) {
         self.ws.close().unwrap();
@@ -0,0 +1,41 @@
+const ANNOUNCEMENT_DURATION: f32 = 8.0;
+
+// message from the server admins, shown as text on top of the canvas for a few seconds.
+// the text is hidden again once this is dropped
+#[derive(Debug)]
+pub struct Announcement {
+    remaining: f32,
+}
+
+impl Announcement {
+    pub fn show(message: &str) -> Self {
+        if let Some(overlay) = overlay() {
+            overlay.set_text_content(Some(message));
+            let _ = overlay.remove_attribute("hidden");
+        }
+
+        Self {
+            remaining: ANNOUNCEMENT_DURATION,
+        }
+    }
+
+    // returns true once it has been shown for long enough
+    pub fn expired(&mut self, dt: f32) -> bool {
+        self.remaining -= dt;
+        self.remaining <= 0.0
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        if let Some(overlay) = overlay() {
+            let _ = overlay.set_attribute("hidden", "");
+        }
+    }
+}
+
+fn overlay() -> Option<web_sys::Element> {
+    web_sys::window()?
+        .document()?
+        .get_element_by_id("Announcement")
+}
@@ -1,117 +1,100 @@
 use super::Scene;
 use crate::engine::{CreateContext, object::Object};
-use common::{RoundInitParams, map::*, map_coord_to_world};
-use nalgebra::Point2;
-use parry2d::shape::Polyline;
+use common::{ClientId, RoundInitParams, map::*, map_coord_to_world, physics::PhysicsMap};
 use poll_promise::Promise;
 
-pub struct Collider(pub Polyline);
-impl std::fmt::Debug for Collider {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Collider").finish()
-    }
-}
-
-#[derive(Debug)]
-pub struct Offroad(pub Vec<Point2<f32>>);
-
 pub trait MapToScene {
     fn to_scene(&self, gl: &CreateContext, params: &RoundInitParams) -> Scene;
+    fn to_spectator_scene(&self, gl: &CreateContext, players: &[(ClientId, String)]) -> Scene;
 }
 
 impl MapToScene for Map {
     fn to_scene(&self, ctx: &CreateContext, params: &RoundInitParams) -> Scene {
-        use crate::game::objects;
-        let objects: Vec<Box<dyn Object>> = Vec::new();
+        build_scene(
+            self,
+            ctx,
+            Some((params.client_id, params.start_pos)),
+            &params.players,
+        )
+    }
+
+    // everyone on the track is an external player
+    fn to_spectator_scene(&self, ctx: &CreateContext, players: &[(ClientId, String)]) -> Scene {
+        build_scene(self, ctx, None, players)
+    }
+}
 
-        let map_image = &self.assets()[self.background.unwrap()].image;
-        let map = objects::Map::new(ctx, &map_image);
+fn build_scene(
+    data: &Map,
+    ctx: &CreateContext,
+    own: Option<(ClientId, usize)>,
+    players: &[(ClientId, String)],
+) -> Scene {
+    use crate::game::objects;
+    let objects: Vec<Box<dyn Object>> = Vec::new();
 
-        let (player_pos, player_rot) = self.track.iter_starts().nth(params.start_pos).unwrap();
+    let map_image = &data.assets()[data.background.unwrap()].image;
+    let map = objects::Map::new(ctx, &map_image);
+
+    let player = own.map(|(_, start_pos)| {
+        let (player_pos, player_rot) = data.track.iter_starts().nth(start_pos).unwrap();
         let player_pos = map_coord_to_world(player_pos);
-        let player = objects::Player::new(ctx, params.start_pos, player_pos, player_rot);
-
-        let players = params
-            .players
-            .iter()
-            .enumerate()
-            .filter(|(i, _)| *i != params.start_pos)
-            .map(|(i, (id, name))| {
-                let (start_pos, start_rot) = self.track.iter_starts().nth(i).unwrap();
-                let start_pos = map_coord_to_world(start_pos);
-                (
-                    *id,
-                    objects::ExternalPlayer::new(ctx, name.clone(), start_pos, start_rot),
-                )
-            })
-            .collect();
-
-        let colliders = self
-            .colliders
-            .iter()
-            .map(|c| {
-                let points = c
-                    .shape
-                    .iter()
-                    .map(|p| Point2::new(p.x, p.y))
-                    .chain(std::iter::once(Point2::new(c.shape[0].x, c.shape[0].y)))
-                    .collect();
-                Collider(Polyline::new(points, None))
-            })
-            .collect();
-
-        let offroad = self
-            .offroad
-            .iter()
-            .map(|c| {
-                let points = c
-                    .shape
-                    .iter()
-                    .map(|p| Point2::new(p.x, p.y))
-                    .chain(std::iter::once(Point2::new(c.shape[0].x, c.shape[0].y)))
-                    .collect();
-                Offroad(points)
-            })
-            .collect();
-
-        let coin_texture = &self.assets()[self.coin.unwrap()].image;
-        let coins = self
-            .coins
-            .iter()
-            .map(|c| {
-                let pos = map_coord_to_world(*c);
-                objects::Coin::new(ctx, coin_texture, pos)
-            })
-            .collect();
-
-        let item_box_texture = &self.assets()[self.item_box.unwrap()].image;
-        let item_boxes = self
-            .item_spawns
-            .iter()
-            .map(|c| {
-                let pos = map_coord_to_world(*c);
-                objects::ItemBox::new(ctx, item_box_texture, pos)
-            })
-            .collect();
-
-        Scene {
-            own_id: params.client_id,
-
-            player,
-            players,
-
-            colliders,
-            offroad,
-
-            item_boxes,
-            coins,
-            items: Vec::new(),
-            explosions: Vec::new(),
-
-            map,
-
-            static_objects: objects,
-        }
+        objects::Player::new(ctx, start_pos, player_pos, player_rot)
+    });
+    let own_start_pos = own.map(|(_, start_pos)| start_pos);
+
+    let players = players
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != own_start_pos)
+        .map(|(i, (id, name))| {
+            let (start_pos, start_rot) = data.track.iter_starts().nth(i).unwrap();
+            let start_pos = map_coord_to_world(start_pos);
+            (
+                *id,
+                objects::ExternalPlayer::new(ctx, name.clone(), start_pos, start_rot),
+            )
+        })
+        .collect();
+
+    let coin_texture = &data.assets()[data.coin.unwrap()].image;
+    let coins = data
+        .coins
+        .iter()
+        .map(|c| {
+            let pos = map_coord_to_world(*c);
+            objects::Coin::new(ctx, coin_texture, pos)
+        })
+        .collect();
+
+    let item_box_texture = &data.assets()[data.item_box.unwrap()].image;
+    let item_boxes = data
+        .item_spawns
+        .iter()
+        .map(|c| {
+            let pos = map_coord_to_world(*c);
+            objects::ItemBox::new(ctx, item_box_texture, pos)
+        })
+        .collect();
+
+    Scene {
+        // spectators don't have an id in the race
+        own_id: own.map_or(ClientId::invalid(), |(id, _)| id),
+
+        player,
+        players,
+
+        physics: PhysicsMap::new(data),
+
+        item_boxes,
+        coins,
+        items: Vec::new(),
+        race_view: Default::default(),
+        explosions: Vec::new(),
+
+        map,
+
+        static_objects: objects,
     }
 }
 
@@ -143,31 +126,42 @@ impl From<wasm_bindgen::JsValue> for MapDownloadError {
     }
 }
 
+pub async fn fetch_bytes(url: &str) -> Result<Vec<u8>, MapDownloadError> {
+    use js_sys::{ArrayBuffer, Uint8Array};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+    use web_sys::{Request, RequestInit, RequestMode, Response};
+
+    let opts = RequestInit::new();
+    opts.set_method("GET");
+    opts.set_mode(RequestMode::Cors);
+
+    let request = Request::new_with_str_and_init(url, &opts)?;
+
+    let window = web_sys::window().unwrap();
+    let res = JsFuture::from(window.fetch_with_request(&request))
+        .await?
+        .dyn_into::<Response>()?;
+    if !res.ok() {
+        return Err(MapDownloadError::Fetch(format!(
+            "'{}' returned status {}",
+            url,
+            res.status()
+        )));
+    }
+
+    let array_buffer = JsFuture::from(res.array_buffer()?)
+        .await?
+        .dyn_into::<ArrayBuffer>()?;
+    Ok(Uint8Array::new(&array_buffer).to_vec())
+}
+
 impl MapDownload {
     pub fn start(url: String) -> Self {
-        use js_sys::{ArrayBuffer, Uint8Array};
         use std::io::Cursor;
-        use wasm_bindgen::JsCast;
-        use wasm_bindgen_futures::JsFuture;
-        use web_sys::{Request, RequestInit, RequestMode, Response};
 
         let promise = Promise::spawn_local(async move {
-            let opts = RequestInit::new();
-            opts.set_method("GET");
-            opts.set_mode(RequestMode::Cors);
-
-            let request = Request::new_with_str_and_init(&url, &opts)?;
-
-            let window = web_sys::window().unwrap();
-            let res = JsFuture::from(window.fetch_with_request(&request))
-                .await?
-                .dyn_into::<Response>()?;
-
-            let array_buffer = JsFuture::from(res.array_buffer()?)
-                .await?
-                .dyn_into::<ArrayBuffer>()?;
-            let buffer = Uint8Array::new(&array_buffer).to_vec();
-
+            let buffer = fetch_bytes(&url).await?;
             let map = Map::load(&mut Cursor::new(&buffer))?;
 
             Ok(map)
@@ -0,0 +1,50 @@
+use wasm_bindgen::JsCast;
+
+const STORAGE_KEY: &str = "name";
+const DEFAULT_NAME: &str = "cool player";
+
+// text field on the main menu. the name is kept in local storage so it is already
+// filled in the next time the game is opened
+pub fn show() {
+    let Some(input) = input() else {
+        return;
+    };
+    if input.value().is_empty() {
+        if let Some(name) = storage().and_then(|s| s.get_item(STORAGE_KEY).ok().flatten()) {
+            input.set_value(&name);
+        }
+    }
+    let _ = input.remove_attribute("hidden");
+}
+
+pub fn hide() {
+    if let Some(input) = input() {
+        let _ = input.set_attribute("hidden", "");
+    }
+}
+
+// the name to register with, remembered for next time
+pub fn name() -> String {
+    let name = input().map(|input| input.value()).unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return DEFAULT_NAME.to_string();
+    }
+
+    if let Some(storage) = storage() {
+        let _ = storage.set_item(STORAGE_KEY, name);
+    }
+    name.to_string()
+}
+
+fn input() -> Option<web_sys::HtmlInputElement> {
+    web_sys::window()?
+        .document()?
+        .get_element_by_id("NameInput")?
+        .dyn_into()
+        .ok()
+}
+
+fn storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
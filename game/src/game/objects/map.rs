@@ -39,6 +39,11 @@ impl Map {
             dimensions,
         }
     }
+
+    // half the width and depth of the map in world units
+    pub fn dimensions(&self) -> Vec2 {
+        self.dimensions
+    }
 }
 
 impl Object for Map {
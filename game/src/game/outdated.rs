@@ -0,0 +1,9 @@
+// covers the whole page after a deploy, the only way forward is loading the new version
+pub fn show() {
+    let overlay = web_sys::window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.get_element_by_id("Outdated"));
+    if let Some(overlay) = overlay {
+        let _ = overlay.remove_attribute("hidden");
+    }
+}
@@ -0,0 +1,207 @@
+use common::{PROTOCOL_VERSION, ServerMessage, SpectateParams, map::Map, replay::Replay};
+use poll_promise::Promise;
+use std::{io::Cursor, rc::Rc};
+
+use super::map::{MapDownloadError, fetch_bytes};
+
+const SEEK_STEP: f32 = 5.0;
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 8.0;
+// explosions that started longer ago than this are over by the time playback continues
+const EXPLOSION_DURATION: f32 = 0.5;
+
+// plays a recorded round back by handing its messages to the game as if they came from the server
+pub struct ReplayPlayer {
+    download: Option<Promise<Result<(Replay, Map), ReplayLoadError>>>,
+    loaded: Option<(Replay, Rc<Map>)>,
+
+    time: f32,
+    next_event: usize,
+    speed: f32,
+    paused: bool,
+    // the scene has to be rebuilt before playing on from `time`
+    restart: bool,
+}
+
+#[derive(Debug)]
+enum ReplayLoadError {
+    Download(MapDownloadError),
+    // recorded on another protocol version, so the messages can't be read
+    Version(Option<u32>),
+}
+
+impl From<MapDownloadError> for ReplayLoadError {
+    fn from(err: MapDownloadError) -> Self {
+        Self::Download(err)
+    }
+}
+
+pub enum Playback {
+    Waiting,
+    // start watching from scratch, then catch up with the messages
+    Restart {
+        map: Rc<Map>,
+        params: SpectateParams,
+        messages: Vec<ServerMessage>,
+    },
+    Messages(Vec<ServerMessage>),
+}
+
+impl std::fmt::Debug for ReplayPlayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayPlayer")
+            .field("time", &self.time)
+            .field("speed", &self.speed)
+            .field("paused", &self.paused)
+            .finish()
+    }
+}
+
+impl ReplayPlayer {
+    pub fn load(url: String) -> Self {
+        let download = Promise::spawn_local(async move {
+            let bytes = fetch_bytes(&url).await?;
+            let version = Replay::peek_version(&bytes);
+            if version != Some(PROTOCOL_VERSION) {
+                return Err(ReplayLoadError::Version(version));
+            }
+            let replay = Replay::from_bytes(&bytes)
+                .map_err(|e| MapDownloadError::Fetch(format!("{:?}", e)))?;
+
+            let bytes = fetch_bytes(&replay.map).await?;
+            let map = Map::load(&mut Cursor::new(&bytes)).map_err(MapDownloadError::from)?;
+
+            Ok((replay, map))
+        });
+
+        Self {
+            download: Some(download),
+            loaded: None,
+
+            time: 0.0,
+            next_event: 0,
+            speed: 1.0,
+            paused: false,
+            restart: true,
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        self.loaded
+            .as_ref()
+            .map_or(0.0, |(replay, _)| replay.duration())
+    }
+
+    // p pauses, j and l seek back and forward, the number keys jump to that tenth of the round,
+    // comma and period change the speed. every other key is left to the spectator camera
+    pub fn key_down(&mut self, key: &str) -> bool {
+        match key {
+            "KeyP" => self.paused = !self.paused,
+            "KeyJ" => self.seek(self.time - SEEK_STEP),
+            "KeyL" => self.seek(self.time + SEEK_STEP),
+            "Comma" => self.speed = (self.speed / 2.0).max(MIN_SPEED),
+            "Period" => self.speed = (self.speed * 2.0).min(MAX_SPEED),
+            _ => {
+                let Some(tenth) = key.strip_prefix("Digit").and_then(|d| d.parse::<u8>().ok())
+                else {
+                    return false;
+                };
+                self.seek(self.duration() * tenth as f32 / 10.0);
+            }
+        }
+
+        log::info!(
+            "replay at {:.1}s/{:.1}s, speed {}x{}",
+            self.time,
+            self.duration(),
+            self.speed,
+            if self.paused { ", paused" } else { "" }
+        );
+        true
+    }
+
+    fn seek(&mut self, time: f32) {
+        self.time = time.clamp(0.0, self.duration());
+        self.restart = true;
+    }
+
+    pub fn update(&mut self, dt: f32) -> Playback {
+        if let Some(download) = self.download.take() {
+            match download.try_take() {
+                Ok(Ok((replay, map))) => {
+                    log::info!(
+                        "loaded replay of '{}' ({:.1}s)",
+                        map.metadata.name,
+                        replay.duration()
+                    );
+                    self.loaded = Some((replay, Rc::new(map)));
+                }
+                Ok(Err(ReplayLoadError::Version(version))) => {
+                    log::error!(
+                        "replay was recorded on protocol version {:?}, expected {}",
+                        version,
+                        PROTOCOL_VERSION
+                    );
+                    crate::alert(
+                        "this replay was recorded on another version of the game and cant be played anymore.",
+                    );
+                }
+                Ok(Err(e)) => {
+                    log::error!("error loading replay: {:?}", e);
+                    crate::alert(
+                        "this replay couldnt be loaded.\nplease check the link and try again.",
+                    );
+                }
+                Err(download) => self.download = Some(download),
+            }
+        }
+
+        let Some((replay, map)) = &self.loaded else {
+            return Playback::Waiting;
+        };
+
+        if !self.paused {
+            self.time = (self.time + dt * self.speed).min(replay.duration());
+        }
+        let end = replay.events.partition_point(|e| e.time <= self.time);
+
+        if !std::mem::take(&mut self.restart) {
+            let messages = replay.events[self.next_event.min(end)..end]
+                .iter()
+                .map(|e| e.message.clone())
+                .collect();
+            self.next_event = end;
+            return Playback::Messages(messages);
+        }
+
+        // only the newest race update matters when catching up, it overwrites all the ones before it
+        let last_update = replay.events[..end]
+            .iter()
+            .rposition(|e| matches!(e.message, ServerMessage::RaceUpdate { .. }));
+        let messages = replay.events[..end]
+            .iter()
+            .enumerate()
+            .filter(|(i, e)| match e.message {
+                ServerMessage::RaceUpdate { .. } => Some(*i) == last_update,
+                ServerMessage::HitByItem { .. } | ServerMessage::ItemsCollided { .. } => {
+                    e.time > self.time - EXPLOSION_DURATION
+                }
+                _ => true,
+            })
+            .map(|(_, e)| e.message.clone())
+            .collect();
+        self.next_event = end;
+
+        Playback::Restart {
+            map: map.clone(),
+            params: SpectateParams {
+                map: replay.map.clone(),
+                players: replay.players.clone(),
+                race_time: None,
+                coin_states: Vec::new(),
+                item_box_states: Vec::new(),
+            },
+            messages,
+        }
+    }
+}
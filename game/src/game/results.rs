@@ -0,0 +1,73 @@
+use common::{ClientId, Placement, RaceRecord};
+
+// names and times are shown as text on top of the canvas since the sprite ui has no font.
+// the overlay is hidden again once this is dropped
+#[derive(Debug)]
+pub struct ResultsOverlay;
+
+impl ResultsOverlay {
+    pub fn show(
+        placements: &[Placement],
+        record: Option<&RaceRecord>,
+        name: impl Fn(ClientId) -> String,
+    ) -> Self {
+        let mut text = String::from("results\n\n");
+        for (i, placement) in placements.iter().enumerate() {
+            let time = placement
+                .finish_time
+                .map_or_else(|| "dnf".to_string(), format_time);
+            text += &format!(
+                "{:>2}. {:<16} {:>8}\n",
+                i + 1,
+                name(placement.client_id),
+                time
+            );
+        }
+
+        if let Some(record) = record {
+            // the record is saved before the results are sent, so it might have been set just now
+            let new_record = placements
+                .iter()
+                .any(|p| p.finish_time == Some(record.time));
+
+            text += &format!(
+                "\nrecord  {}  {}{}\n",
+                format_time(record.time),
+                record.name,
+                if new_record { "  new record!" } else { "" }
+            );
+            let laps: Vec<_> = record.laps.iter().copied().map(format_time).collect();
+            text += &format!("laps    {}\n", laps.join("  "));
+        }
+
+        if let Some(overlay) = overlay() {
+            overlay.set_text_content(Some(&text));
+            let _ = overlay.remove_attribute("hidden");
+        }
+
+        Self
+    }
+}
+
+impl Drop for ResultsOverlay {
+    fn drop(&mut self) {
+        if let Some(overlay) = overlay() {
+            let _ = overlay.set_attribute("hidden", "");
+        }
+    }
+}
+
+fn overlay() -> Option<web_sys::Element> {
+    web_sys::window()?.document()?.get_element_by_id("Results")
+}
+
+// m:ss.cc
+fn format_time(secs: f32) -> String {
+    let centis = (secs * 100.0).round() as u32;
+    format!(
+        "{}:{:02}.{:02}",
+        centis / 6000,
+        centis / 100 % 60,
+        centis % 100
+    )
+}
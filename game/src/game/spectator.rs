@@ -0,0 +1,174 @@
+use crate::engine::Camera;
+use common::{ClientId, types::*};
+use std::collections::{HashMap, HashSet};
+
+use super::objects::ExternalPlayer;
+
+const OVERHEAD_PITCH: f32 = -50.0;
+const OVERHEAD_YAW: f32 = -90.0;
+const PAN_SPEED: f32 = 10.0;
+const ZOOM_SPEED: f32 = 1.5;
+const MIN_DISTANCE: f32 = 2.0;
+
+// camera for watching a race, either chasing one of the racers or looking at the track from above
+#[derive(Debug)]
+pub struct Spectator {
+    view: View,
+    camera_angle: f32,
+
+    map_size: Vec2,
+    focus: Vec3,
+    distance: f32,
+    max_distance: f32,
+    held_keys: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum View {
+    Follow(ClientId),
+    Overhead,
+}
+
+impl Spectator {
+    pub fn new(map_size: Vec2) -> Self {
+        // far enough away to see the whole map with the default fov
+        let max_distance = map_size.max_element() / 30.0_f32.to_radians().tan();
+
+        Self {
+            view: View::Overhead,
+            camera_angle: 0.0,
+
+            map_size,
+            focus: Vec3::ZERO,
+            distance: max_distance,
+            max_distance,
+            held_keys: HashSet::new(),
+        }
+    }
+
+    // space switches between following a racer and the overhead view,
+    // left and right cycle through the racers in the order they are placed
+    pub fn key_down(&mut self, key: &str, players: &HashMap<ClientId, ExternalPlayer>) {
+        match (key, self.view) {
+            ("Space" | "KeyV", View::Follow(_)) => self.view = View::Overhead,
+            ("Space" | "KeyV", View::Overhead) => self.cycle(players, 0),
+            ("ArrowLeft" | "KeyA", View::Follow(_)) => self.cycle(players, -1),
+            ("ArrowRight" | "KeyD", View::Follow(_)) => self.cycle(players, 1),
+            _ => {
+                self.held_keys.insert(key.to_string());
+            }
+        }
+    }
+
+    pub fn key_up(&mut self, key: &str) {
+        self.held_keys.remove(key);
+    }
+
+    // place of the racer that is being followed
+    pub fn target_place(&self, players: &HashMap<ClientId, ExternalPlayer>) -> Option<usize> {
+        let View::Follow(target) = self.view else {
+            return None;
+        };
+        standings(players)
+            .iter()
+            .position(|id| *id == target)
+            .map(|i| i + 1)
+    }
+
+    pub fn update(
+        &mut self,
+        dt: f32,
+        players: &HashMap<ClientId, ExternalPlayer>,
+        cam: &mut Camera,
+    ) {
+        // the racer we were following might have left
+        if let View::Follow(target) = self.view {
+            if !players.contains_key(&target) {
+                self.cycle(players, 0);
+            }
+        }
+
+        match self.view {
+            View::Follow(target) => {
+                let player = &players[&target];
+                self.camera_angle = f32::lerp(self.camera_angle, player.physical_rot(), dt * 5.0);
+
+                // same spot behind the kart as the player's own camera
+                let camera_forward = Vec3::new(
+                    self.camera_angle.to_radians().cos(),
+                    0.0,
+                    self.camera_angle.to_radians().sin(),
+                );
+                cam.transform.pos = Vec3::new(player.pos.x, 0.0, player.pos.z)
+                    - camera_forward * 2.5
+                    + Vec3::new(0.0, 1.0, 0.0);
+                cam.transform.rot = Rotation::new(-5.0, self.camera_angle, 0.0);
+            }
+            View::Overhead => {
+                let held = |keys: [&str; 2]| keys.iter().any(|k| self.held_keys.contains(*k));
+                let axis = |negative: bool, positive: bool| {
+                    positive as i32 as f32 - negative as i32 as f32
+                };
+
+                let pan = Vec3::new(
+                    axis(held(["ArrowLeft", "KeyA"]), held(["ArrowRight", "KeyD"])),
+                    0.0,
+                    axis(held(["ArrowUp", "KeyW"]), held(["ArrowDown", "KeyS"])),
+                );
+                let zoom = axis(held(["KeyQ", "Equal"]), held(["KeyE", "Minus"]));
+
+                // move slower when zoomed in
+                let pan_speed = PAN_SPEED * (self.distance / self.max_distance).max(0.2);
+                self.focus += pan * pan_speed * dt;
+                self.focus.x = self.focus.x.clamp(-self.map_size.x, self.map_size.x);
+                self.focus.z = self.focus.z.clamp(-self.map_size.y, self.map_size.y);
+
+                self.distance *= ZOOM_SPEED.powf(zoom * dt);
+                self.distance = self.distance.clamp(MIN_DISTANCE, self.max_distance * 1.5);
+
+                let (pitch, yaw) = (OVERHEAD_PITCH.to_radians(), OVERHEAD_YAW.to_radians());
+                let direction = Vec3::new(
+                    pitch.cos() * yaw.cos(),
+                    pitch.sin(),
+                    pitch.cos() * yaw.sin(),
+                );
+                cam.transform.pos = self.focus - direction * self.distance;
+                cam.transform.rot = Rotation::new(OVERHEAD_PITCH, OVERHEAD_YAW, 0.0);
+            }
+        }
+
+        cam.set_fov(60.0);
+    }
+
+    fn cycle(&mut self, players: &HashMap<ClientId, ExternalPlayer>, offset: isize) {
+        let standings = standings(players);
+        if standings.is_empty() {
+            self.view = View::Overhead;
+            return;
+        }
+
+        let next = match self.view {
+            View::Follow(target) => standings
+                .iter()
+                .position(|id| *id == target)
+                .map(|i| (i as isize + offset).rem_euclid(standings.len() as isize) as usize)
+                .unwrap_or(0),
+            // start with the leader
+            View::Overhead => 0,
+        };
+
+        let target = standings[next];
+        if self.view != View::Follow(target) {
+            // don't swing the camera around from wherever the last racer was facing
+            self.camera_angle = players[&target].physical_rot();
+        }
+        self.view = View::Follow(target);
+    }
+}
+
+// racers from first to last place
+fn standings(players: &HashMap<ClientId, ExternalPlayer>) -> Vec<ClientId> {
+    let mut standings: Vec<_> = players.iter().collect();
+    standings.sort_by(|(_, a), (_, b)| b.track_pos().cmp(a.track_pos()));
+    standings.into_iter().map(|(id, _)| *id).collect()
+}
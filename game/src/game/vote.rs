@@ -0,0 +1,226 @@
+use crate::engine::{CreateContext, RenderContext, ui::*};
+use common::{MapCandidate, types::*};
+use image::DynamicImage;
+use poll_promise::Promise;
+
+use super::map::{MapDownloadError, fetch_bytes};
+
+const THUMBNAIL_WIDTH: f32 = 18.0;
+const THUMBNAIL_SPACING: f32 = 22.0;
+// below the center of the screen
+const THUMBNAIL_Y: f32 = 15.0;
+const HIGHLIGHT_SCALE: f32 = 1.2;
+
+// names, authors and the remaining time are shown as text on top of the canvas since the sprite
+// ui has no font. the text is removed again once the vote is dropped
+pub struct MapVote {
+    candidates: Vec<Candidate>,
+    voted: Option<usize>,
+    remaining: f32,
+    countdown: Option<web_sys::Element>,
+    // the countdown text only changes once a second
+    shown_seconds: Option<u32>,
+    pub click: bool,
+}
+
+struct Candidate {
+    info: MapCandidate,
+    download: Option<Promise<Result<DynamicImage, MapDownloadError>>>,
+    sprite: Option<UiSprite>,
+    label: Option<web_sys::Element>,
+}
+
+impl std::fmt::Debug for MapVote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MapVote")
+            .field(
+                "candidates",
+                &self.candidates.iter().map(|c| &c.info).collect::<Vec<_>>(),
+            )
+            .field("voted", &self.voted)
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl MapVote {
+    pub fn new(candidates: Vec<MapCandidate>, duration: f32) -> Self {
+        let overlay = overlay();
+        if let Some(overlay) = &overlay {
+            let _ = overlay.remove_attribute("hidden");
+        }
+
+        let candidates = candidates
+            .into_iter()
+            .map(|info| {
+                let download = info.thumbnail.clone().map(|url| {
+                    Promise::spawn_local(async move {
+                        let bytes = fetch_bytes(&url).await?;
+                        image::load_from_memory(&bytes)
+                            .map_err(|e| MapDownloadError::Fetch(format!("{:?}", e)))
+                    })
+                });
+
+                // only shown once the thumbnail is there, since its height decides where the label goes
+                let label = overlay.as_ref().and_then(|overlay| {
+                    let label = add_element(overlay, "MapVoteLabel")?;
+                    label.set_text_content(Some(&format!("{}\nby {}", info.name, info.author)));
+                    let _ = label.set_attribute("hidden", "");
+                    Some(label)
+                });
+
+                Candidate {
+                    info,
+                    download,
+                    sprite: None,
+                    label,
+                }
+            })
+            .collect();
+
+        let countdown = overlay
+            .as_ref()
+            .and_then(|overlay| add_element(overlay, "MapVoteCountdown"));
+
+        Self {
+            candidates,
+            voted: None,
+            remaining: duration,
+            countdown,
+            shown_seconds: None,
+            click: false,
+        }
+    }
+
+    // number keys vote for the candidate at that position
+    pub fn key_down(&mut self, key: &str) -> Option<usize> {
+        let number: usize = key
+            .strip_prefix("Digit")
+            .or_else(|| key.strip_prefix("Numpad"))?
+            .parse()
+            .ok()?;
+
+        let index = number.checked_sub(1)?;
+        if index < self.candidates.len() {
+            self.voted = Some(index);
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    // returns the candidate that was clicked, if any
+    pub fn update(&mut self, ctx: &CreateContext, mouse_pos: Vec2, dt: f32) -> Option<usize> {
+        self.remaining = (self.remaining - dt).max(0.0);
+        let seconds = self.remaining.ceil() as u32;
+        if self.shown_seconds != Some(seconds) {
+            self.shown_seconds = Some(seconds);
+            if let Some(countdown) = &self.countdown {
+                countdown.set_text_content(Some(&format!("vote for the next map: {}s", seconds)));
+            }
+        }
+
+        let count = self.candidates.len();
+        for (i, candidate) in self.candidates.iter_mut().enumerate() {
+            if candidate.sprite.is_some() {
+                continue;
+            }
+
+            let x = (i as f32 - (count - 1) as f32 / 2.0) * THUMBNAIL_SPACING;
+            let pos = UiVec::new(Pct(x), Pct(THUMBNAIL_Y));
+            let sprite = match candidate.download.take().map(|d| d.try_take()) {
+                None => UiSprite::load_single(ctx, "logo.png", pos),
+                Some(Ok(Ok(img))) => {
+                    let url = candidate.info.thumbnail.as_deref().unwrap_or_default();
+                    let name = format!("thumbnail:{}", url);
+                    UiSprite::from_image(ctx, &name, &img, pos)
+                }
+                Some(Ok(Err(e))) => {
+                    log::warn!(
+                        "failed to load thumbnail for '{}': {:?}",
+                        candidate.info.name,
+                        e
+                    );
+                    UiSprite::load_single(ctx, "logo.png", pos)
+                }
+                Some(Err(download)) => {
+                    candidate.download = Some(download);
+                    continue;
+                }
+            };
+
+            // the canvas covers the whole page, and the thumbnail width is a percentage of half
+            // its width. the label stays below the thumbnail even when it is highlighted
+            if let Some(label) = &candidate.label {
+                let below = THUMBNAIL_WIDTH / 2.0 * HIGHLIGHT_SCALE * sprite.aspect();
+                let style = format!(
+                    "left: calc(50% + {}vw); top: calc({}% + {}vw); width: {}vw",
+                    x,
+                    50.0 + THUMBNAIL_Y,
+                    below,
+                    THUMBNAIL_WIDTH * HIGHLIGHT_SCALE
+                );
+                let _ = label.set_attribute("style", &style);
+                let _ = label.remove_attribute("hidden");
+            }
+            candidate.sprite = Some(sprite.anchor(Anchor::CENTER));
+        }
+
+        let hovered = self.candidates.iter().position(|c| {
+            c.sprite
+                .as_ref()
+                .is_some_and(|s| s.hovered(ctx.viewport, mouse_pos))
+        });
+
+        // the hovered and the voted for map are shown a bit bigger
+        for (i, candidate) in self.candidates.iter_mut().enumerate() {
+            if let Some(sprite) = &mut candidate.sprite {
+                let highlight = Some(i) == hovered || Some(i) == self.voted;
+                let width = if highlight {
+                    THUMBNAIL_WIDTH * HIGHLIGHT_SCALE
+                } else {
+                    THUMBNAIL_WIDTH
+                };
+                sprite.width = Pct(width).into();
+            }
+        }
+
+        if std::mem::take(&mut self.click) && hovered.is_some() {
+            self.voted = hovered;
+            hovered
+        } else {
+            None
+        }
+    }
+
+    pub fn render(&self, ctx: &RenderContext) {
+        for sprite in self.candidates.iter().filter_map(|c| c.sprite.as_ref()) {
+            sprite.render(ctx);
+        }
+    }
+}
+
+impl Drop for MapVote {
+    // a new vote might already be showing, so only remove what belongs to this one
+    fn drop(&mut self) {
+        let labels = self.candidates.iter().filter_map(|c| c.label.as_ref());
+        for element in labels.chain(&self.countdown) {
+            element.remove();
+        }
+
+        if let Some(overlay) = overlay().filter(|overlay| overlay.child_element_count() == 0) {
+            let _ = overlay.set_attribute("hidden", "");
+        }
+    }
+}
+
+fn overlay() -> Option<web_sys::Element> {
+    web_sys::window()?.document()?.get_element_by_id("MapVote")
+}
+
+fn add_element(parent: &web_sys::Element, class: &str) -> Option<web_sys::Element> {
+    let element = parent.owner_document()?.create_element("div").ok()?;
+    element.set_class_name(class);
+    parent.append_child(&element).ok()?;
+    Some(element)
+}
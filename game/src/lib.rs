@@ -52,45 +52,10 @@ pub fn start() {
 
     let gl = glow::Context::from_webgl2_context(webgl2_context);
 
-    let (ws, rx) = {
-        let ws_protocol = if window.location().protocol().unwrap() == "https:" {
-            "wss"
-        } else {
-            "ws"
-        };
-
-        let server_host = window.location().host().unwrap();
-
-        let ws = WebSocket::new(&format!("{}://{}/ws", ws_protocol, server_host)).unwrap();
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-
-        let (tx, rx) = mpsc::channel();
-        let on_message = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-            if let Ok(buf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
-                let array = js_sys::Uint8Array::new(&buf);
-                match ServerMessage::from_bytes(&array.to_vec()) {
-                    Ok(msg) => {
-                        tx.send(msg).unwrap();
-                    }
-                    Err(e) => {
-                        log::warn!("Error parsing message: {:?}", e);
-                    }
-                }
-            }
-        });
-        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
-        on_message.forget();
+    let (tx, rx) = mpsc::channel();
+    let ws = open_socket(tx.clone());
 
-        let on_error = Closure::<dyn FnMut(_)>::new(|e: ErrorEvent| {
-            log::error!("WebSocket error: {:?}", e);
-        });
-        ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
-        on_error.forget();
-
-        (ws, rx)
-    };
-
-    let game = Rc::new(RefCell::new(Game::new(ws.clone(), rx, gl, dim)));
+    let game = Rc::new(RefCell::new(Game::new(ws.clone(), tx, rx, gl, dim)));
     let on_open = Closure::<dyn FnMut()>::new(move || {
         let window = web_sys::window().unwrap();
         let performance = window.performance().unwrap();
@@ -226,6 +191,45 @@ pub fn start() {
     on_open.forget();
 }
 
+// messages from every socket end up in the same channel, so the game doesn't care about reconnects
+pub fn open_socket(tx: mpsc::Sender<ServerMessage>) -> WebSocket {
+    let window = web_sys::window().unwrap();
+    let ws_protocol = if window.location().protocol().unwrap() == "https:" {
+        "wss"
+    } else {
+        "ws"
+    };
+
+    let server_host = window.location().host().unwrap();
+
+    let ws = WebSocket::new(&format!("{}://{}/ws", ws_protocol, server_host)).unwrap();
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+
+    let on_message = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+        if let Ok(buf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+            let array = js_sys::Uint8Array::new(&buf);
+            match ServerMessage::from_bytes(&array.to_vec()) {
+                Ok(msg) => {
+                    tx.send(msg).unwrap();
+                }
+                Err(e) => {
+                    log::warn!("Error parsing message: {:?}", e);
+                }
+            }
+        }
+    });
+    ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    on_message.forget();
+
+    let on_error = Closure::<dyn FnMut(_)>::new(|e: ErrorEvent| {
+        log::error!("WebSocket error: {:?}", e);
+    });
+    ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    on_error.forget();
+
+    ws
+}
+
 fn request_animation_frame(f: &Closure<dyn FnMut()>) {
     web_sys::window()
         .unwrap()
@@ -0,0 +1,113 @@
+use axum::{
+    Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+};
+use common::ClientId;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::server::{AdminError, GameServerHandle, RoomInfo};
+
+// everything below /api/admin, only reachable with the token from the config
+pub fn router(token: String) -> Router<Arc<GameServerHandle>> {
+    Router::new()
+        .route("/rooms", get(rooms))
+        .route("/rooms/:room/end-round", post(end_round))
+        .route("/rooms/:room/next-map", post(next_map))
+        .route("/clients/:client/kick", post(kick))
+        .route("/announce", post(announce))
+        .layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            auth,
+        ))
+}
+
+async fn auth(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if tokens_match(provided.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+// looks at every byte so the time it takes doesn't tell how much of the token was right
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AdminError::RoomNotFound => (StatusCode::NOT_FOUND, "room not found"),
+            AdminError::ClientNotFound => (StatusCode::NOT_FOUND, "client not found"),
+            AdminError::MapNotFound => (StatusCode::NOT_FOUND, "map not found"),
+            AdminError::NotRacing => (StatusCode::CONFLICT, "room is not racing"),
+        };
+        (status, message).into_response()
+    }
+}
+
+async fn rooms(State(server): State<Arc<GameServerHandle>>) -> Json<Vec<RoomInfo>> {
+    Json(server.rooms().await)
+}
+
+async fn end_round(
+    State(server): State<Arc<GameServerHandle>>,
+    Path(room): Path<u32>,
+) -> Result<StatusCode, AdminError> {
+    server.end_round(room).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct NextMap {
+    // path of the map as listed in the room info, like `maps/mario_circuit_1/mario_circuit_1.smk`
+    map: String,
+}
+
+async fn next_map(
+    State(server): State<Arc<GameServerHandle>>,
+    Path(room): Path<u32>,
+    Json(request): Json<NextMap>,
+) -> Result<StatusCode, AdminError> {
+    server.set_next_map(room, &request.map)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn kick(
+    State(server): State<Arc<GameServerHandle>>,
+    Path(client): Path<u32>,
+) -> Result<StatusCode, AdminError> {
+    // 0 is the invalid client id and can't be constructed
+    if client == 0 {
+        return Err(AdminError::ClientNotFound);
+    }
+    server.kick_client(ClientId::new(client)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct Announcement {
+    message: String,
+    // every room gets the announcement if this is left out
+    room: Option<u32>,
+}
+
+async fn announce(
+    State(server): State<Arc<GameServerHandle>>,
+    Json(request): Json<Announcement>,
+) -> Result<StatusCode, AdminError> {
+    server.announce(request.room, request.message).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
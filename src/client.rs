@@ -1,25 +1,78 @@
-use common::{ClientId, PlayerState};
+use common::{
+    ClientId, ItemKind, PlayerState, TrackPosition, physics::HIT_DURATION, types::Smooth,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
-use crate::server::SerializedServerMessage;
+use crate::{metrics::METRICS, server::SerializedServerMessage};
+
+// how much a new round trip time measurement changes the estimate
+const RTT_SMOOTHING: f32 = 0.25;
 
 #[derive(Debug)]
 pub struct Client {
     id: ClientId,
     name: String,
-    tx: mpsc::Sender<SerializedServerMessage>,
+    addr: IpAddr,
+    // bots are driven by the server and have no connection
+    tx: Option<mpsc::Sender<SerializedServerMessage>>,
     pub state: PlayerState,
+    pub track_pos: TrackPosition,
+    pub item: Option<ItemKind>,
+    // the server owns the coin count, clients only get told about it
+    pub coins: u32,
+    hit_time: Option<Instant>,
+    // how long each lap of the current race took
+    pub lap_times: Vec<f32>,
     pub load_failures: u8,
+    // smoothed round trip time in seconds, none until the first ping came back
+    rtt: Option<f32>,
+    // spectators stay in the lobby and only watch the races
+    pub spectator: bool,
 }
 
 impl Client {
-    pub fn new(id: ClientId, name: String, tx: mpsc::Sender<SerializedServerMessage>) -> Self {
+    pub fn new(
+        id: ClientId,
+        name: String,
+        addr: IpAddr,
+        tx: mpsc::Sender<SerializedServerMessage>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            addr,
+            tx: Some(tx),
+            state: PlayerState::default(),
+            track_pos: TrackPosition::default(),
+            item: None,
+            coins: 0,
+            hit_time: None,
+            lap_times: Vec::new(),
+            load_failures: 0,
+            rtt: None,
+            spectator: false,
+        }
+    }
+
+    pub fn bot(id: ClientId, name: String) -> Self {
         Self {
             id,
             name,
-            tx,
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+            tx: None,
             state: PlayerState::default(),
+            track_pos: TrackPosition::default(),
+            item: None,
+            coins: 0,
+            hit_time: None,
+            lap_times: Vec::new(),
             load_failures: 0,
+            rtt: None,
+            spectator: false,
         }
     }
 
@@ -31,11 +84,88 @@ impl Client {
         &self.name
     }
 
+    pub fn rename(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn reset_race(&mut self, start_state: PlayerState) {
+        self.state = start_state;
+        self.track_pos = TrackPosition::default();
+        self.item = None;
+        self.coins = 0;
+        self.hit_time = None;
+        self.lap_times.clear();
+    }
+
+    pub fn complete_lap(&mut self, race_time: f32) {
+        let previous_laps: f32 = self.lap_times.iter().sum();
+        self.lap_times.push(race_time - previous_laps);
+    }
+
+    pub fn pick_up_coin(&mut self) {
+        self.coins = (self.coins + 1).min(10);
+    }
+
+    // karts still spinning from the last hit don't lose coins again
+    pub fn hit(&mut self) {
+        let now = Instant::now();
+        let spinning = self
+            .hit_time
+            .is_some_and(|t| (now - t).as_secs_f32() < HIT_DURATION);
+        if spinning {
+            return;
+        }
+        self.hit_time = Some(now);
+
+        self.coins = if self.coins < 2 {
+            0
+        } else {
+            self.coins - (self.coins / 2 - 1)
+        };
+    }
+
+    pub fn update_rtt(&mut self, rtt: Duration) {
+        let rtt = rtt.as_secs_f32();
+        self.rtt = Some(match self.rtt {
+            Some(old) => f32::lerp(old, rtt, RTT_SMOOTHING),
+            None => rtt,
+        });
+    }
+
+    // how long messages take to get from the server to the client, in seconds
+    pub fn latency(&self) -> f32 {
+        self.rtt.unwrap_or(0.0) / 2.0
+    }
+
+    pub fn is_bot(&self) -> bool {
+        self.tx.is_none()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.tx.as_ref().is_none_or(|tx| !tx.is_closed())
+    }
+
+    pub fn reconnect(&mut self, tx: mpsc::Sender<SerializedServerMessage>) {
+        self.tx = Some(tx);
+    }
+
     pub async fn send<M: Into<SerializedServerMessage>>(&self, message: M) {
+        // messages to disconnected clients are dropped until they reconnect
+        let Some(tx) = self.tx.as_ref().filter(|tx| !tx.is_closed()) else {
+            return;
+        };
+
         let message = message.into();
-        match self.tx.send(message).await {
+        match tx.send(message).await {
             Ok(_) => {}
-            Err(e) => log::error!("error sending command to client: {}", e),
+            Err(e) => {
+                log::error!("error sending command to client: {}", e);
+                METRICS.send_failures.inc();
+            }
         }
     }
 
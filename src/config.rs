@@ -0,0 +1,534 @@
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::server::{ItemTable, RotationPolicy};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// every setting can also be given on the command line, which takes precedence over the config file
+#[derive(Debug, Parser)]
+#[command(about = "super mimi kart game server")]
+struct Args {
+    /// config file to load, defaults to ./config.toml if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// address to listen on
+    #[arg(long)]
+    bind: Option<SocketAddr>,
+    /// directory containing the built game, editor and assets
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+    /// directory to load maps from
+    #[arg(long)]
+    maps_dir: Option<PathBuf>,
+    /// directory to save race replays to
+    #[arg(long)]
+    replays_dir: Option<PathBuf>,
+    /// directory to keep the leaderboard in
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// open connections allowed per ip address, 0 allows any number
+    #[arg(long)]
+    connections_per_ip: Option<usize>,
+    /// shared secret for the admin api, which is disabled without one
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// maximum number of players per room
+    #[arg(long)]
+    max_players: Option<usize>,
+    /// seconds to wait for more players before a round starts
+    #[arg(long)]
+    lobby_wait: Option<f64>,
+    /// seconds players have to vote for the next map
+    #[arg(long)]
+    vote_duration: Option<f64>,
+    /// seconds a disconnected player keeps their spot for
+    #[arg(long)]
+    reconnect_grace: Option<f64>,
+    /// rounds are filled up with bots until this many are racing, 0 disables bots
+    #[arg(long)]
+    min_players: Option<usize>,
+
+    /// server ticks per second
+    #[arg(long)]
+    tick_rate: Option<f32>,
+    /// seconds players have to load the map
+    #[arg(long)]
+    load_timeout: Option<f64>,
+    /// maximum length of a race in seconds
+    #[arg(long)]
+    race_timeout: Option<f64>,
+    /// seconds until the race ends after the first player finished
+    #[arg(long)]
+    finish_timeout: Option<f64>,
+    /// seconds until coins and item boxes come back
+    #[arg(long)]
+    pickup_respawn: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub static_dir: PathBuf,
+    // persistent server data like the leaderboard
+    pub data_dir: PathBuf,
+    pub maps: MapsConfig,
+    pub replays: ReplaysConfig,
+    pub room: RoomConfig,
+    pub race: RaceConfig,
+    pub items: ItemTable,
+    pub bots: BotsConfig,
+    pub limits: LimitsConfig,
+    pub anti_cheat: AntiCheatConfig,
+    pub names: NamesConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapsConfig {
+    pub dir: PathBuf,
+    pub rotation: RotationPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplaysConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    // the oldest replays are deleted once there are more than this, 0 keeps all of them
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    pub max_players: usize,
+    #[serde(deserialize_with = "secs")]
+    pub lobby_wait: Duration,
+    pub vote_candidates: usize,
+    #[serde(deserialize_with = "secs")]
+    pub vote_duration: Duration,
+    #[serde(deserialize_with = "secs")]
+    pub reconnect_grace: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaceConfig {
+    // server ticks per second, items move the same speed at any rate
+    pub tick_rate: f32,
+    #[serde(deserialize_with = "secs")]
+    pub load_timeout: Duration,
+    #[serde(deserialize_with = "secs")]
+    pub race_timeout: Duration,
+    #[serde(deserialize_with = "secs")]
+    pub finish_timeout: Duration,
+    #[serde(deserialize_with = "secs")]
+    pub pickup_respawn: Duration,
+
+    // karts and items further away from a player than this, in world units, are only sent
+    // to them every `far_update_interval` ticks. 0 sends everything every tick
+    pub interest_radius: f32,
+    pub far_update_interval: u32,
+    // how often the full race state is sent instead of only what changed
+    #[serde(deserialize_with = "secs")]
+    pub keyframe_interval: Duration,
+
+    // how often the connection of every player is pinged to measure their latency
+    #[serde(deserialize_with = "secs")]
+    pub ping_interval: Duration,
+    // item hits are tested against where the item's owner saw the other karts, but never
+    // further back than this. 0 turns lag compensation off
+    #[serde(deserialize_with = "secs")]
+    pub max_rewind: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotsConfig {
+    // rounds are filled up with bots until this many are racing, 0 disables bots
+    pub min_players: usize,
+    // how far bots stray from the middle of the track, in map units
+    pub line_noise: f32,
+    // seconds it takes bots to react to the track ahead of them
+    pub reaction_time: f32,
+    // chance per second that a bot uses the item it is holding
+    pub item_usage: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // largest websocket message a client may send, in bytes
+    pub max_message_size: usize,
+    // clients are disconnected after sending this many messages that can't be decoded
+    pub max_invalid_messages: u32,
+    // 0 allows any number of connections
+    pub connections_per_ip: usize,
+
+    // messages over these limits are dropped
+    pub player_update: RateLimit,
+    pub pick_up: RateLimit,
+    pub use_item: RateLimit,
+    // every other kind of message
+    pub other: RateLimit,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    // messages per second
+    pub rate: f32,
+    // messages that can be sent at once after not sending any for a while
+    pub burst: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AntiCheatConfig {
+    // player updates are checked against what a kart can actually do
+    pub enabled: bool,
+    // how much faster than the fastest kart players may move before it counts, 1.2 is 20% faster
+    pub speed_tolerance: f32,
+    // every implausible update adds to a player's score, which goes down by `score_decay`
+    // every second. players reaching `kick_score` are kicked
+    pub kick_score: f32,
+    pub score_decay: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamesConfig {
+    // in characters, after control and invisible characters have been removed
+    pub min_length: usize,
+    pub max_length: usize,
+    // names containing any of these are rejected, ignoring case, spaces and punctuation
+    pub deny_list: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // requests to /api/admin need an `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read '{}': {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config '{}': {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            static_dir: PathBuf::from("./static"),
+            data_dir: PathBuf::from("./data"),
+            maps: MapsConfig::default(),
+            replays: ReplaysConfig::default(),
+            room: RoomConfig::default(),
+            race: RaceConfig::default(),
+            items: ItemTable::default(),
+            bots: BotsConfig::default(),
+            limits: LimitsConfig::default(),
+            anti_cheat: AntiCheatConfig::default(),
+            names: NamesConfig::default(),
+            admin: AdminConfig::default(),
+        }
+    }
+}
+
+impl Default for MapsConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./maps"),
+            rotation: RotationPolicy::default(),
+        }
+    }
+}
+
+impl Default for ReplaysConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from("./replays"),
+            keep: 500,
+        }
+    }
+}
+
+impl Default for BotsConfig {
+    fn default() -> Self {
+        Self {
+            min_players: 4,
+            line_noise: 10.0,
+            reaction_time: 0.2,
+            item_usage: 0.5,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4096,
+            max_invalid_messages: 10,
+            connections_per_ip: 4,
+
+            // players send an update every tick
+            player_update: RateLimit {
+                rate: common::TICKS_PER_SECOND * 1.25,
+                burst: 30.0,
+            },
+            // driving through a row of coins picks them up in quick succession
+            pick_up: RateLimit {
+                rate: 20.0,
+                burst: 10.0,
+            },
+            use_item: RateLimit {
+                rate: 5.0,
+                burst: 5.0,
+            },
+            other: RateLimit {
+                rate: 5.0,
+                burst: 10.0,
+            },
+        }
+    }
+}
+
+impl Default for AntiCheatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            speed_tolerance: 1.2,
+            kick_score: 10.0,
+            score_decay: 1.0,
+        }
+    }
+}
+
+impl Default for NamesConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            // the results only have room for this many
+            max_length: 16,
+            deny_list: Vec::new(),
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        // shorter wait while developing
+        let lobby_wait = if cfg!(debug_assertions) { 5 } else { 10 };
+
+        Self {
+            max_players: 12,
+            lobby_wait: Duration::from_secs(lobby_wait),
+            vote_candidates: 4,
+            vote_duration: Duration::from_secs(10),
+            reconnect_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for RaceConfig {
+    fn default() -> Self {
+        Self {
+            tick_rate: common::TICKS_PER_SECOND,
+            load_timeout: Duration::from_secs(10),
+            race_timeout: Duration::from_secs(60 * 3),
+            finish_timeout: Duration::from_secs(60),
+            pickup_respawn: Duration::from_secs(1),
+
+            interest_radius: 25.0,
+            far_update_interval: 4,
+            keyframe_interval: Duration::from_secs(1),
+
+            ping_interval: Duration::from_secs(2),
+            max_rewind: Duration::from_millis(250),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        config.apply_args(args)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.into(), e))
+    }
+
+    fn apply_args(&mut self, args: Args) -> Result<(), ConfigError> {
+        fn duration(secs: Option<f64>, target: &mut Duration) -> Result<(), ConfigError> {
+            if let Some(secs) = secs {
+                *target = Duration::try_from_secs_f64(secs)
+                    .map_err(|e| ConfigError::Invalid(format!("{} seconds: {}", secs, e)))?;
+            }
+            Ok(())
+        }
+
+        if let Some(bind) = args.bind {
+            self.bind = bind;
+        }
+        if let Some(static_dir) = args.static_dir {
+            self.static_dir = static_dir;
+        }
+        if let Some(data_dir) = args.data_dir {
+            self.data_dir = data_dir;
+        }
+        if let Some(maps_dir) = args.maps_dir {
+            self.maps.dir = maps_dir;
+        }
+        if let Some(replays_dir) = args.replays_dir {
+            self.replays.dir = replays_dir;
+        }
+        if let Some(connections_per_ip) = args.connections_per_ip {
+            self.limits.connections_per_ip = connections_per_ip;
+        }
+        if let Some(admin_token) = args.admin_token {
+            self.admin.token = Some(admin_token);
+        }
+        if let Some(max_players) = args.max_players {
+            self.room.max_players = max_players;
+        }
+        if let Some(min_players) = args.min_players {
+            self.bots.min_players = min_players;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            self.race.tick_rate = tick_rate;
+        }
+
+        duration(args.lobby_wait, &mut self.room.lobby_wait)?;
+        duration(args.vote_duration, &mut self.room.vote_duration)?;
+        duration(args.reconnect_grace, &mut self.room.reconnect_grace)?;
+        duration(args.load_timeout, &mut self.race.load_timeout)?;
+        duration(args.race_timeout, &mut self.race.race_timeout)?;
+        duration(args.finish_timeout, &mut self.race.finish_timeout)?;
+        duration(args.pickup_respawn, &mut self.race.pickup_respawn)?;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.room.max_players == 0 {
+            return invalid("room.max_players must be at least 1");
+        }
+        if self.room.vote_candidates == 0 {
+            return invalid("room.vote_candidates must be at least 1");
+        }
+        if !(self.race.tick_rate > 0.0 && self.race.tick_rate <= 1000.0) {
+            return invalid("race.tick_rate must be between 0 and 1000");
+        }
+        if self.race.load_timeout.is_zero() {
+            return invalid("race.load_timeout must be longer than 0 seconds");
+        }
+        if self.race.race_timeout.is_zero() {
+            return invalid("race.race_timeout must be longer than 0 seconds");
+        }
+        if self.race.interest_radius.is_nan() || self.race.interest_radius < 0.0 {
+            return invalid("race.interest_radius can't be negative");
+        }
+        if self.race.far_update_interval == 0 {
+            return invalid("race.far_update_interval must be at least 1");
+        }
+        if self.race.keyframe_interval.is_zero() {
+            return invalid("race.keyframe_interval must be longer than 0 seconds");
+        }
+        if self.race.ping_interval.is_zero() {
+            return invalid("race.ping_interval must be longer than 0 seconds");
+        }
+        if self.bots.min_players > self.room.max_players {
+            return invalid("bots.min_players can't be above room.max_players");
+        }
+        if !(self.bots.line_noise >= 0.0
+            && self.bots.reaction_time >= 0.0
+            && self.bots.item_usage >= 0.0)
+        {
+            return invalid("bots.line_noise, reaction_time and item_usage can't be negative");
+        }
+        if self.limits.max_message_size < 256 {
+            return invalid("limits.max_message_size must be at least 256 bytes");
+        }
+        if self.limits.max_invalid_messages == 0 {
+            return invalid("limits.max_invalid_messages must be at least 1");
+        }
+        let rates = [
+            self.limits.player_update,
+            self.limits.pick_up,
+            self.limits.use_item,
+            self.limits.other,
+        ];
+        if rates.iter().any(|r| !(r.rate > 0.0 && r.burst >= 1.0)) {
+            return invalid("limits rates must be above 0 with a burst of at least 1");
+        }
+        if self.anti_cheat.speed_tolerance.is_nan() || self.anti_cheat.speed_tolerance < 1.0 {
+            return invalid("anti_cheat.speed_tolerance must be at least 1");
+        }
+        if !(self.anti_cheat.kick_score > 0.0 && self.anti_cheat.score_decay >= 0.0) {
+            return invalid(
+                "anti_cheat.kick_score must be above 0 and score_decay can't be negative",
+            );
+        }
+        if self.names.min_length == 0 {
+            return invalid("names.min_length must be at least 1");
+        }
+        // leaves space for the number that is added to duplicate names
+        if self.names.max_length < self.names.min_length.max(4) {
+            return invalid("names.max_length must be at least 4 and not below names.min_length");
+        }
+        if self.admin.token.as_ref().is_some_and(|t| t.len() < 16) {
+            return invalid("admin.token must be at least 16 characters long");
+        }
+        self.items.validate().map_err(ConfigError::Invalid)?;
+
+        Ok(())
+    }
+}
+
+// durations are written as (fractional) seconds
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
@@ -1,44 +1,114 @@
 use axum::{
     Router,
     extract::{
-        ConnectInfo, State,
+        ConnectInfo, Path, Query, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
-    response::IntoResponse,
+    http::header,
+    response::{IntoResponse, Json},
     routing::get,
 };
 use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
 use std::{
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
+use tokio::sync::oneshot;
 use tower_http::{compression::CompressionLayer, services::ServeDir};
 
-use common::{ClientMessage, ServerMessage};
+use common::{ClientMessage, PROTOCOL_VERSION, ServerMessage};
 
 mod server;
-use server::{GameServer, GameServerHandle};
+use server::{
+    GameServer, GameServerHandle, Leaderboard, LeaderboardEntry, MapPool, ReplayEntry,
+    replay_entries,
+};
 
 mod client;
 
+mod admin;
+
+mod metrics;
+use metrics::METRICS;
+
+mod rate_limit;
+use rate_limit::MessageLimiter;
+
+mod config;
+use config::Config;
+
+const LEADERBOARD_DEFAULT_LIMIT: usize = 10;
+const LEADERBOARD_MAX_LIMIT: usize = 100;
+
 #[tokio::main]
 async fn main() {
     colog::init();
 
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     let app = Router::new();
 
-    let serve_game_dir = ServeDir::new("./static/game").append_index_html_on_directories(true);
-    let serve_editor_dir = ServeDir::new("./static/editor").append_index_html_on_directories(true);
-    let serve_assets_dir = ServeDir::new("./static/assets").append_index_html_on_directories(false);
-    let serve_maps_dir = ServeDir::new("./static/maps").append_index_html_on_directories(false);
+    let static_dir = &config.static_dir;
+    let serve_game_dir =
+        ServeDir::new(static_dir.join("game")).append_index_html_on_directories(true);
+    let serve_editor_dir =
+        ServeDir::new(static_dir.join("editor")).append_index_html_on_directories(true);
+    let serve_assets_dir =
+        ServeDir::new(static_dir.join("assets")).append_index_html_on_directories(false);
+    // served straight from the scanned directory so the paths sent to clients always exist
+    let serve_maps_dir = ServeDir::new(&config.maps.dir).append_index_html_on_directories(false);
+    let serve_replays_dir =
+        ServeDir::new(&config.replays.dir).append_index_html_on_directories(false);
+    let replays_dir = config.replays.dir.clone();
 
-    let server = Arc::new(GameServer::new());
+    let maps = Arc::new(MapPool::new(&config.maps.dir, config.maps.rotation.clone()));
+    maps.rescan().await;
+    #[cfg(unix)]
+    tokio::spawn(rescan_maps_on_sighup(maps.clone()));
+
+    let data_dir = config.data_dir.clone();
+    let leaderboard = tokio::task::spawn_blocking(move || Leaderboard::load(&data_dir))
+        .await
+        .map(Arc::new)
+        .unwrap();
+
+    let server = Arc::new(GameServer::new(config.clone(), maps, leaderboard.clone()));
+
+    let app = match &config.admin.token {
+        Some(token) => app.nest("/api/admin", admin::router(token.clone())),
+        None => {
+            log::info!("no admin token configured, the admin api is disabled");
+            app
+        }
+    };
 
     let app = app
         .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            "/api/replays",
+            get(move || replay_list(replays_dir.clone())),
+        )
+        .route(
+            "/api/leaderboard/*map",
+            get(move |Path(map), Query(query)| leaderboard_top(leaderboard.clone(), map, query)),
+        )
         .nest_service("/editor", serve_editor_dir)
         .nest_service("/assets", serve_assets_dir)
         .nest_service("/maps", serve_maps_dir)
+        .nest_service("/replays", serve_replays_dir)
         .fallback_service(serve_game_dir)
         .with_state(server);
 
@@ -49,7 +119,7 @@ async fn main() {
         .deflate(true);
     let app = app.layer(compression);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(config.bind).await.unwrap();
 
     log::info!(
         "ready! listening on port {}",
@@ -63,29 +133,117 @@ async fn main() {
     .unwrap();
 }
 
+#[cfg(unix)]
+async fn rescan_maps_on_sighup(maps: Arc<MapPool>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            log::warn!(
+                "failed to listen for SIGHUP, map rescans are disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        log::info!("received SIGHUP, rescanning maps");
+        maps.rescan().await;
+    }
+}
+
+// saved replays, newest first. play one with `?replay=<name>`, as long as it is marked playable
+async fn replay_list(dir: PathBuf) -> Json<Vec<ReplayEntry>> {
+    let replays = tokio::task::spawn_blocking(move || replay_entries(&dir))
+        .await
+        .unwrap_or_default();
+    Json(replays)
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderboardQuery {
+    limit: Option<usize>,
+}
+
+// best finish of every player on a map, fastest first. maps are referred to by path, like
+// `/api/leaderboard/maps/mario_circuit_1/mario_circuit_1.smk?limit=10`
+async fn leaderboard_top(
+    leaderboard: Arc<Leaderboard>,
+    map: String,
+    query: LeaderboardQuery,
+) -> Json<Vec<LeaderboardEntry>> {
+    let limit = query
+        .limit
+        .unwrap_or(LEADERBOARD_DEFAULT_LIMIT)
+        .min(LEADERBOARD_MAX_LIMIT);
+    Json(leaderboard.top(&map, limit))
+}
+
+async fn metrics_handler(State(server): State<Arc<GameServerHandle>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&server).await,
+    )
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(server): State<Arc<GameServerHandle>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_client(socket, server, addr.ip()))
+    let max_size = server.config().limits.max_message_size;
+    ws.max_message_size(max_size)
+        .max_frame_size(max_size)
+        .on_upgrade(move |socket| handle_client(socket, server, addr.ip()))
 }
 
 async fn handle_client(socket: WebSocket, server: Arc<GameServerHandle>, addr: IpAddr) {
-    let client_id = server.allocate_client();
-    log::info!("({}) client connecting", client_id);
-
     let (mut socket_tx, mut socket_rx) = socket.split();
 
-    let client_name = if let Some(Ok(Message::Binary(msg))) = socket_rx.next().await {
+    // held until the socket is closed
+    let _connection = match server.open_connection(addr) {
+        Ok(connection) => connection,
+        Err(e) => {
+            let _ = socket_tx
+                .send(Message::Binary(e.message().to_bytes().unwrap()))
+                .await;
+            return;
+        }
+    };
+
+    let first_msg = if let Some(Ok(Message::Binary(msg))) = socket_rx.next().await {
+        // clients on another version can't be understood, but can still be told to reload
+        let outdated = ClientMessage::peek_version(&msg).filter(|v| *v != PROTOCOL_VERSION);
+        if let Some(version) = outdated {
+            log::info!(
+                "({}) client on protocol version {} has to reload",
+                addr,
+                version
+            );
+            METRICS.outdated_clients.inc();
+            let msg = ServerMessage::OutdatedClient {
+                server_version: PROTOCOL_VERSION,
+            };
+            let _ = socket_tx
+                .send(Message::Binary(msg.to_bytes().unwrap()))
+                .await;
+            return;
+        }
+
         match ClientMessage::from_bytes(&msg) {
-            Ok(ClientMessage::Register { name }) => name,
+            Ok(msg @ (ClientMessage::Register { .. } | ClientMessage::Resume { .. })) => {
+                METRICS.message_received(&msg);
+                msg
+            }
             Ok(_) => {
                 log::warn!("client didnt register before sending data");
                 return;
             }
             Err(e) => {
                 log::warn!("client sent invalid register message: {}", e);
+                METRICS.invalid_messages.inc();
                 return;
             }
         }
@@ -93,38 +251,121 @@ async fn handle_client(socket: WebSocket, server: Arc<GameServerHandle>, addr: I
         log::warn!("client didnt send register message");
         return;
     };
-    log::info!(
-        "({}, {}) client connected with name '{}'",
-        client_id,
-        addr,
-        client_name
-    );
 
-    let mut msg_rx = match server.register_client(client_id, addr, client_name).await {
-        Some(msg_rx) => msg_rx,
-        None => {
+    let joined = match first_msg {
+        ClientMessage::Resume { token, .. } => {
+            let resumed = server.resume_client(&token).await;
+            if let Ok((client_id, _)) = &resumed {
+                log::info!("({}, {}) client resumed their session", client_id, addr);
+            }
+            resumed
+        }
+        ClientMessage::Register {
+            name,
+            room,
+            spectate,
+            ..
+        } => {
+            let client_id = server.allocate_client();
+            log::info!(
+                "({}, {}) client connected with name '{}'{}",
+                client_id,
+                addr,
+                name,
+                if spectate { " as spectator" } else { "" }
+            );
+            server
+                .register_client(client_id, addr, name, room, spectate)
+                .await
+                .map(|msg_rx| (client_id, msg_rx))
+        }
+        _ => unreachable!(),
+    };
+
+    let (client_id, mut msg_rx) = match joined {
+        Ok(joined) => joined,
+        Err(e) => {
+            log::warn!("({}) client could not join: {:?}", addr, e);
             let _ = socket_tx
-                .send(Message::Binary(
-                    ServerMessage::DuplicateLogin.to_bytes().unwrap(),
-                ))
+                .send(Message::Binary(e.message().to_bytes().unwrap()))
                 .await;
             return;
         }
     };
 
-    let rx_task = {
+    // pings carry when they were sent, in microseconds since the client joined. clients could
+    // send made up pongs, but that only makes them look slower than they are
+    let joined_at = Instant::now();
+
+    let (closed_tx, mut closed_rx) = oneshot::channel::<()>();
+    let mut rx_task = {
         let server = server.clone();
+        let limits = server.config().limits.clone();
         tokio::spawn(async move {
-            while let Some(Ok(Message::Binary(msg))) = socket_rx.next().await {
+            let mut limiter = MessageLimiter::new(&limits);
+            let mut invalid_messages = 0;
+            let mut warned_rate_limit = false;
+
+            loop {
+                let msg = tokio::select! {
+                    msg = socket_rx.next() => msg,
+                    // stop reading right away once this connection is closed, so an old
+                    // connection can't keep driving a kart after its session was resumed
+                    _ = &mut closed_rx => break,
+                };
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                let msg = match msg {
+                    Message::Binary(msg) => msg,
+                    Message::Pong(payload) => {
+                        if let Ok(sent) = <[u8; 8]>::try_from(payload.as_slice()) {
+                            let sent = Duration::from_micros(u64::from_le_bytes(sent));
+                            let rtt = joined_at.elapsed().saturating_sub(sent);
+                            server.measured_rtt(client_id, rtt).await;
+                        }
+                        continue;
+                    }
+                    // answered by axum
+                    Message::Ping(_) => continue,
+                    _ => break,
+                };
+
                 let msg = match ClientMessage::from_bytes(&msg) {
                     Ok(msg) => msg,
                     Err(e) => {
-                        log::warn!("client sent invalid message: {}", e);
+                        log::warn!("({}) client sent invalid message: {}", client_id, e);
+                        METRICS.invalid_messages.inc();
+
+                        invalid_messages += 1;
+                        if invalid_messages >= limits.max_invalid_messages {
+                            log::warn!(
+                                "({}) disconnecting client after {} invalid messages",
+                                client_id,
+                                invalid_messages
+                            );
+                            break;
+                        }
                         continue;
                     }
                 };
+                METRICS.message_received(&msg);
+
+                if !limiter.allow(&msg) {
+                    METRICS.rate_limited.inc();
+                    if !std::mem::replace(&mut warned_rate_limit, true) {
+                        log::warn!(
+                            "({}) client is sending too many messages, dropping some",
+                            client_id
+                        );
+                    }
+                    continue;
+                }
 
-                if matches!(msg, ClientMessage::Register { .. }) {
+                if matches!(
+                    msg,
+                    ClientMessage::Register { .. } | ClientMessage::Resume { .. }
+                ) {
                     log::warn!("client tried to register again");
                     continue;
                 }
@@ -134,20 +375,52 @@ async fn handle_client(socket: WebSocket, server: Arc<GameServerHandle>, addr: I
         })
     };
 
-    let tx_task = tokio::spawn(async move {
-        while let Some(msg) = msg_rx.recv().await {
-            if let Err(e) = socket_tx.send(Message::Binary(msg.bytes().to_vec())).await {
-                log::warn!("error sending message to client: {}", e);
-                break;
+    let mut ping_interval = tokio::time::interval(server.config().race.ping_interval);
+    // set before the kick reaches the client, which might close the socket as soon as it sees it
+    let kicked = Arc::new(AtomicBool::new(false));
+    let mut tx_task = {
+        let kicked = kicked.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    msg = msg_rx.recv() => match msg {
+                        Some(msg) => {
+                            if msg.kicked() {
+                                kicked.store(true, Ordering::Relaxed);
+                            }
+                            Message::Binary(msg.bytes().to_vec())
+                        }
+                        None => break,
+                    },
+                    _ = ping_interval.tick() => {
+                        let sent = joined_at.elapsed().as_micros() as u64;
+                        Message::Ping(sent.to_le_bytes().to_vec())
+                    }
+                };
+                if let Err(e) = socket_tx.send(msg).await {
+                    log::warn!("error sending message to client: {}", e);
+                    return;
+                }
             }
-        }
-    });
+            // the server dropped the client, e.g. because it was kicked or resumed on another connection
+            let _ = closed_tx.send(());
+            let _ = socket_tx.send(Message::Close(None)).await;
+        })
+    };
 
     tokio::select! {
-        _ = rx_task => (),
-        _ = tx_task => ()
+        _ = &mut rx_task => (),
+        _ = &mut tx_task => ()
     }
+    // the socket stays open for as long as either half of it is alive
+    rx_task.abort();
+    tx_task.abort();
 
-    server.remove_client(client_id, addr).await;
+    // kicked clients can't come back, so there is nothing to wait for
+    if kicked.load(Ordering::Relaxed) {
+        server.remove_client(client_id).await;
+    } else {
+        server.client_disconnected(client_id);
+    }
     log::info!("({}) client disconnected", client_id);
 }
@@ -0,0 +1,248 @@
+use common::ClientMessage;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::server::{ClientState, GameServerHandle};
+
+// counters are bumped from all over the server, so they live in a global.
+// gauges are collected from the rooms whenever the metrics are scraped
+pub static METRICS: Metrics = Metrics::new();
+
+const MESSAGE_KINDS: [&str; 8] = [
+    "register",
+    "resume",
+    "loaded_map",
+    "vote_map",
+    "pick_up",
+    "use_item",
+    "player_update",
+    "finish_round",
+];
+
+// upper bounds in seconds
+const TICK_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+#[derive(Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub rounds_started: Counter,
+    pub rounds_completed: Counter,
+    pub load_timeouts: Counter,
+    pub invalid_messages: Counter,
+    pub rate_limited: Counter,
+    pub send_failures: Counter,
+    pub outdated_clients: Counter,
+    pub corrected_updates: Counter,
+    pub cheat_kicks: Counter,
+    pub rejected_pickups: Counter,
+    messages_received: [Counter; MESSAGE_KINDS.len()],
+
+    // not cumulative, each bucket only counts the ticks between its bound and the one before
+    tick_buckets: [Counter; TICK_BUCKETS.len() + 1],
+    tick_sum_micros: Counter,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            rounds_started: Counter::new(),
+            rounds_completed: Counter::new(),
+            load_timeouts: Counter::new(),
+            invalid_messages: Counter::new(),
+            rate_limited: Counter::new(),
+            send_failures: Counter::new(),
+            outdated_clients: Counter::new(),
+            corrected_updates: Counter::new(),
+            cheat_kicks: Counter::new(),
+            rejected_pickups: Counter::new(),
+            messages_received: [const { Counter::new() }; MESSAGE_KINDS.len()],
+
+            tick_buckets: [const { Counter::new() }; TICK_BUCKETS.len() + 1],
+            tick_sum_micros: Counter::new(),
+        }
+    }
+
+    pub fn message_received(&self, msg: &ClientMessage) {
+        let kind = match msg {
+            ClientMessage::Register { .. } => 0,
+            ClientMessage::Resume { .. } => 1,
+            ClientMessage::LoadedMap => 2,
+            ClientMessage::VoteMap(_) => 3,
+            ClientMessage::PickUp { .. } => 4,
+            ClientMessage::UseItem(_) => 5,
+            ClientMessage::PlayerUpdate(_) => 6,
+            ClientMessage::FinishRound { .. } => 7,
+        };
+        self.messages_received[kind].inc();
+    }
+
+    pub fn tick(&self, duration: Duration) {
+        let bucket = TICK_BUCKETS
+            .iter()
+            .position(|bound| duration.as_secs_f64() <= *bound)
+            .unwrap_or(TICK_BUCKETS.len());
+        self.tick_buckets[bucket].inc();
+        self.tick_sum_micros.add(duration.as_micros() as u64);
+    }
+}
+
+// everything in the prometheus text format
+pub async fn render(server: &GameServerHandle) -> String {
+    let rooms = server.rooms().await;
+    let m = &METRICS;
+
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(out, "# HELP smk_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE smk_{} {}", name, kind);
+    }
+
+    let mut out = String::new();
+
+    header(&mut out, "rooms", "gauge", "Open rooms.");
+    let _ = writeln!(out, "smk_rooms {}", rooms.len());
+
+    header(&mut out, "clients", "gauge", "Connected clients by state.");
+    for state in ClientState::ALL {
+        let count = rooms
+            .iter()
+            .flat_map(|room| &room.status.clients)
+            .filter(|c| c.connected && !c.bot && c.state == state)
+            .count();
+        let _ = writeln!(out, "smk_clients{{state=\"{}\"}} {}", state.label(), count);
+    }
+
+    header(&mut out, "bots", "gauge", "Bots racing.");
+    let bots = rooms
+        .iter()
+        .flat_map(|room| &room.status.clients)
+        .filter(|c| c.bot)
+        .count();
+    let _ = writeln!(out, "smk_bots {}", bots);
+
+    header(
+        &mut out,
+        "active_items",
+        "gauge",
+        "Items currently on the tracks.",
+    );
+    let active_items: usize = rooms.iter().map(|room| room.status.active_items).sum();
+    let _ = writeln!(out, "smk_active_items {}", active_items);
+
+    let counters = [
+        ("rounds_started_total", "Rounds started.", &m.rounds_started),
+        (
+            "rounds_completed_total",
+            "Rounds completed.",
+            &m.rounds_completed,
+        ),
+        (
+            "load_timeouts_total",
+            "Clients that took too long to load a map.",
+            &m.load_timeouts,
+        ),
+        (
+            "invalid_messages_total",
+            "Client messages that couldn't be decoded.",
+            &m.invalid_messages,
+        ),
+        (
+            "rate_limited_total",
+            "Client messages dropped for going over the rate limits.",
+            &m.rate_limited,
+        ),
+        (
+            "send_failures_total",
+            "Messages that couldn't be queued for a client.",
+            &m.send_failures,
+        ),
+        (
+            "outdated_clients_total",
+            "Clients told to reload because they are on another protocol version.",
+            &m.outdated_clients,
+        ),
+        (
+            "corrected_updates_total",
+            "Player updates corrected for moving karts in impossible ways.",
+            &m.corrected_updates,
+        ),
+        (
+            "cheat_kicks_total",
+            "Clients kicked for too many implausible player updates.",
+            &m.cheat_kicks,
+        ),
+        (
+            "rejected_pickups_total",
+            "Pickups claimed by players too far away from them.",
+            &m.rejected_pickups,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "smk_{} {}", name, counter.get());
+    }
+
+    header(
+        &mut out,
+        "messages_received_total",
+        "counter",
+        "Client messages received by kind.",
+    );
+    for (kind, counter) in MESSAGE_KINDS.iter().zip(&m.messages_received) {
+        let _ = writeln!(
+            out,
+            "smk_messages_received_total{{kind=\"{}\"}} {}",
+            kind,
+            counter.get()
+        );
+    }
+
+    header(
+        &mut out,
+        "tick_duration_seconds",
+        "histogram",
+        "Time a room takes for a game tick.",
+    );
+    let mut count = 0;
+    for (i, bucket) in m.tick_buckets.iter().enumerate() {
+        count += bucket.get();
+        let bound = TICK_BUCKETS
+            .get(i)
+            .map_or_else(|| "+Inf".to_string(), |b| b.to_string());
+        let _ = writeln!(
+            out,
+            "smk_tick_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "smk_tick_duration_seconds_sum {}",
+        m.tick_sum_micros.get() as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "smk_tick_duration_seconds_count {}", count);
+
+    out
+}
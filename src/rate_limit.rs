@@ -0,0 +1,131 @@
+use common::ClientMessage;
+use std::time::Instant;
+
+use crate::config::{LimitsConfig, RateLimit};
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// limits how often a single connection can send each kind of message
+#[derive(Debug)]
+pub struct MessageLimiter {
+    player_update: TokenBucket,
+    pick_up: TokenBucket,
+    use_item: TokenBucket,
+    other: TokenBucket,
+}
+
+impl MessageLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            player_update: TokenBucket::new(config.player_update),
+            pick_up: TokenBucket::new(config.pick_up),
+            use_item: TokenBucket::new(config.use_item),
+            other: TokenBucket::new(config.other),
+        }
+    }
+
+    // returns false if the message is over the limit and should be dropped
+    pub fn allow(&mut self, msg: &ClientMessage) -> bool {
+        let bucket = match msg {
+            ClientMessage::PlayerUpdate(_) => &mut self.player_update,
+            ClientMessage::PickUp { .. } => &mut self.pick_up,
+            ClientMessage::UseItem(_) => &mut self.use_item,
+            _ => &mut self.other,
+        };
+        bucket.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{PickupKind, PlayerState};
+    use std::time::Duration;
+
+    fn bucket(rate: f32, burst: f32) -> TokenBucket {
+        TokenBucket::new(RateLimit { rate, burst })
+    }
+
+    // pretends the last refill happened this long ago
+    fn wait(bucket: &mut TokenBucket, secs: f32) {
+        bucket.last_refill -= Duration::from_secs_f32(secs);
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let mut bucket = bucket(10.0, 3.0);
+        assert!((0..3).all(|_| bucket.take()));
+        assert!(!bucket.take());
+
+        // a quarter second refills two and a half messages
+        wait(&mut bucket, 0.25);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let mut bucket = bucket(10.0, 3.0);
+        assert!((0..3).all(|_| bucket.take()));
+
+        wait(&mut bucket, 60.0);
+        assert!((0..3).all(|_| bucket.take()));
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn message_kinds_have_their_own_limit() {
+        let one = RateLimit {
+            rate: 1.0,
+            burst: 1.0,
+        };
+        let mut limiter = MessageLimiter::new(&LimitsConfig {
+            player_update: one,
+            pick_up: one,
+            use_item: one,
+            other: one,
+            ..LimitsConfig::default()
+        });
+
+        let update = ClientMessage::PlayerUpdate(PlayerState::default());
+        let pick_up = ClientMessage::PickUp {
+            kind: PickupKind::Coin,
+            index: 0,
+        };
+        assert!(limiter.allow(&update));
+        assert!(!limiter.allow(&update));
+        assert!(limiter.allow(&pick_up));
+        assert!(!limiter.allow(&pick_up));
+        assert!(limiter.allow(&ClientMessage::LoadedMap));
+        assert!(!limiter.allow(&ClientMessage::VoteMap(0)));
+    }
+}
@@ -1,60 +1,156 @@
-use common::{
-    COUNTDOWN_DURATION, ClientId, ClientMessage, RoundInitParams, ServerMessage, TICKS_PER_SECOND,
-    map::Map,
-};
-use rand::seq::SliceRandom;
+use common::{ClientId, ClientMessage, NameRejection, RoomRequest, ServerMessage};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::Serialize;
 use std::{
-    collections::HashSet,
-    fs::File,
+    collections::HashMap,
     net::IpAddr,
     sync::{
         Arc, Mutex,
         atomic::{AtomicU32, Ordering},
     },
-    time::{Duration, Instant},
-};
-use tokio::{
-    sync::mpsc,
-    time::{interval, sleep},
+    time::Duration,
 };
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
 
-use crate::client::Client;
+use crate::{client::Client, config::Config};
+
+mod anti_cheat;
+mod bots;
 
 mod client_handler;
-use client_handler::{ClientManager, ClientManagerHandle, SendTo, TickResult};
+pub use client_handler::ClientState;
+use client_handler::{ClientManagerHandle, RoomStatus};
 
 mod game_state;
+mod item_table;
+pub use item_table::ItemTable;
+
+mod leaderboard;
+pub use leaderboard::{Leaderboard, LeaderboardEntry};
 
-const MAPS: [&str; 4] = [
-    "maps/mario_circuit_1/mario_circuit_1.smk",
-    "maps/mario_circuit_3/mario_circuit_3.smk",
-    "maps/donut_plains_1/donut_plains_1.smk",
-    "maps/donut_plains_3/donut_plains_3.smk",
-];
+mod map_pool;
+pub use map_pool::{MapPool, RotationPolicy};
+
+mod names;
+
+mod replays;
+pub use replays::{ReplayEntry, replay_entries};
+
+mod room;
+use room::{Room, RoomHandle, RoomId};
 
 #[derive(Debug)]
 pub struct GameServerHandle {
     next_client_id: AtomicU32,
-    clients: ClientManagerHandle,
-    connected_ips: Arc<Mutex<HashSet<IpAddr>>>,
+    server: Mutex<GameServer>,
+    config: Arc<Config>,
+    // open websocket connections per ip address
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// counts towards the connection limit of its ip address until it is dropped
+#[derive(Debug)]
+pub struct Connection {
+    addr: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.addr);
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct GameServer {
-    clients: ClientManagerHandle,
+    next_room_id: u32,
+    config: Arc<Config>,
+    maps: Arc<MapPool>,
+    leaderboard: Arc<Leaderboard>,
+    rooms: HashMap<RoomId, RoomHandle>,
+    private_rooms: HashMap<String, RoomId>,
+    client_rooms: HashMap<ClientId, RoomId>,
+    sessions: HashMap<String, ClientId>,
+    client_sessions: HashMap<ClientId, String>,
+    // pending session expiry of disconnected clients
+    expiry_timers: HashMap<ClientId, AbortHandle>,
 }
 
+#[derive(Debug)]
+pub enum JoinError {
+    TooManyConnections,
+    RoomNotFound,
+    RoomFull,
+    SessionExpired,
+    InvalidName(NameRejection),
+}
+
+impl JoinError {
+    pub fn message(&self) -> ServerMessage {
+        match self {
+            JoinError::TooManyConnections => ServerMessage::TooManyConnections,
+            JoinError::RoomNotFound => ServerMessage::RoomNotFound,
+            JoinError::RoomFull => ServerMessage::RoomFull,
+            JoinError::SessionExpired => ServerMessage::SessionExpired,
+            JoinError::InvalidName(reason) => ServerMessage::NameRejected(*reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    RoomNotFound,
+    ClientNotFound,
+    MapNotFound,
+    NotRacing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub code: Option<String>,
+    pub in_round: bool,
+    pub player_count: usize,
+    #[serde(flatten)]
+    pub status: RoomStatus,
+}
+
+const ROOM_CODE_LENGTH: usize = 4;
+// no 0/O or 1/I so codes can be read out loud
+const ROOM_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const SESSION_TOKEN_LENGTH: usize = 32;
+
 #[derive(Debug, Clone)]
-pub struct SerializedServerMessage(Arc<[u8]>);
+pub struct SerializedServerMessage {
+    bytes: Arc<[u8]>,
+    // the room kicked the client, so their session ends with the connection
+    kicked: bool,
+}
 
 impl SerializedServerMessage {
     pub fn new(msg: ServerMessage) -> Self {
+        let kicked = matches!(msg, ServerMessage::Kicked);
         let msg = msg.to_bytes().expect("message serialization to never fail");
-        Self(msg.into())
+        Self {
+            bytes: msg.into(),
+            kicked,
+        }
     }
 
     pub fn bytes(&self) -> &[u8] {
-        &self.0
+        &self.bytes
+    }
+
+    pub fn kicked(&self) -> bool {
+        self.kicked
     }
 }
 
@@ -65,105 +161,178 @@ impl From<ServerMessage> for SerializedServerMessage {
 }
 
 impl GameServer {
-    pub fn new() -> GameServerHandle {
-        let clients = ClientManager::new();
-        let connected_ips = Arc::new(Mutex::new(HashSet::new()));
-
+    pub fn new(
+        config: Arc<Config>,
+        maps: Arc<MapPool>,
+        leaderboard: Arc<Leaderboard>,
+    ) -> GameServerHandle {
         let server = Self {
-            clients: clients.clone(),
+            next_room_id: 1,
+            config: config.clone(),
+            maps,
+            leaderboard,
+            rooms: HashMap::new(),
+            private_rooms: HashMap::new(),
+            client_rooms: HashMap::new(),
+            sessions: HashMap::new(),
+            client_sessions: HashMap::new(),
+            expiry_timers: HashMap::new(),
         };
 
-        tokio::spawn(server.run());
-
         GameServerHandle {
             next_client_id: AtomicU32::new(1),
-            clients,
-            connected_ips,
+            server: Mutex::new(server),
+            config,
+            connections: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    async fn run(self) {
-        loop {
-            self.clients.await_client().await;
-
-            #[cfg(not(debug_assertions))]
-            let wait_time = 10;
-            #[cfg(debug_assertions)]
-            let wait_time = 5;
+    fn create_room(&mut self, code: Option<String>) -> RoomId {
+        let id = RoomId::new(self.next_room_id);
+        self.next_room_id += 1;
 
-            log::info!("waiting {} seconds for players to join", wait_time);
-            tokio::time::sleep(Duration::from_secs(wait_time)).await;
-
-            let map_path = MAPS.choose(&mut rand::thread_rng()).unwrap();
-            log::info!("waiting for players to load map '{:?}'", map_path);
+        match &code {
+            Some(code) => {
+                log::info!("room {}: created private room with code {}", id, code);
+                self.private_rooms.insert(code.clone(), id);
+            }
+            None => log::info!("room {}: created", id),
+        }
 
-            let load_map = tokio::task::spawn_blocking(move || {
-                let file = File::open(map_path)?;
-                Map::load(file)
-            });
+        self.rooms.insert(
+            id,
+            Room::new(
+                id,
+                code,
+                self.maps.clone(),
+                self.leaderboard.clone(),
+                self.config.clone(),
+            ),
+        );
+        id
+    }
 
-            let map = match load_map.await.unwrap() {
-                Ok(map) => map,
-                Err(e) => {
-                    log::error!("failed to load map '{map_path}': {:?}", e);
-                    continue;
-                }
-            };
+    fn generate_room_code(&self) -> String {
+        let mut rng = rand::thread_rng();
+        loop {
+            let code: String = (0..ROOM_CODE_LENGTH)
+                .map(|_| ROOM_CODE_CHARS[rng.gen_range(0..ROOM_CODE_CHARS.len())] as char)
+                .collect();
 
-            let mut starting_clients = self.clients.load_map(map_path, map).await;
-            starting_clients.shuffle(&mut rand::thread_rng());
-
-            for (i, (id, _)) in starting_clients.iter().enumerate() {
-                self.clients
-                    .send(
-                        SendTo::InGameOnly(*id),
-                        ServerMessage::StartRound {
-                            params: RoundInitParams {
-                                client_id: *id,
-                                start_pos: i,
-                                players: starting_clients.clone(),
-                            },
-                        },
-                    )
-                    .await;
+            if !self.private_rooms.contains_key(&code) {
+                break code;
             }
+        }
+    }
 
-            log::info!("waiting for clients to load in");
-            tokio::time::sleep(Duration::from_secs(1)).await;
-
-            self.clients
-                .send(SendTo::InGameAll, ServerMessage::StartCountdown)
-                .await;
-            log::info!("waiting for race countdown to finish");
-            tokio::time::sleep(Duration::from_secs_f32(COUNTDOWN_DURATION)).await;
-
-            log::info!("round started with players: {:?}", starting_clients);
-            self.clients
-                .send(SendTo::InGameAll, ServerMessage::StartRace)
-                .await;
-            let race_start = Instant::now();
-            let race_timeout = sleep(Duration::from_secs(60 * 3));
-            tokio::pin!(race_timeout);
-
-            let mut tick_interval =
-                interval(Duration::from_secs_f64(1.0 / TICKS_PER_SECOND as f64));
-
-            loop {
-                tokio::select! {
-                    _ = tick_interval.tick() => {
-                        let race_time = race_start.elapsed().as_secs_f32();
-                        match self.clients.game_tick(race_time).await {
-                            TickResult::RaceOver => break,
-                            TickResult::NoChange => {}
+    // public players go into the fullest room that can still take players,
+    // or into a new one if every room is full or currently racing.
+    // spectators don't mind joining in the middle of a race
+    fn join_room(
+        &mut self,
+        client_id: ClientId,
+        request: RoomRequest,
+        spectate: bool,
+    ) -> Result<(ClientManagerHandle, Option<String>), JoinError> {
+        let room_id = match request {
+            RoomRequest::Public => {
+                let room_id = self
+                    .rooms
+                    .values()
+                    .filter(|room| {
+                        if spectate {
+                            room.is_watchable()
+                        } else {
+                            room.is_joinable()
                         }
-                    }
-                    _ = &mut race_timeout => break,
+                    })
+                    .max_by_key(|room| room.player_count())
+                    .map(|room| room.id());
+                room_id.unwrap_or_else(|| self.create_room(None))
+            }
+            RoomRequest::CreatePrivate => {
+                let code = self.generate_room_code();
+                self.create_room(Some(code))
+            }
+            RoomRequest::JoinPrivate(code) => {
+                let code = code.trim().to_uppercase();
+                let room_id = *self
+                    .private_rooms
+                    .get(&code)
+                    .ok_or(JoinError::RoomNotFound)?;
+                if self.rooms[&room_id].is_full() {
+                    return Err(JoinError::RoomFull);
                 }
+                room_id
             }
+        };
+
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.player_joined();
+        self.client_rooms.insert(client_id, room_id);
+
+        log::info!(
+            "({}) joined room {} ({} players)",
+            client_id,
+            room_id,
+            room.player_count()
+        );
+
+        Ok((room.clients().clone(), room.code().map(str::to_string)))
+    }
+
+    // returns the room the client was in, plus the room itself if it is now empty and should be shut down
+    fn leave_room(
+        &mut self,
+        client_id: ClientId,
+    ) -> Option<(ClientManagerHandle, Option<RoomHandle>)> {
+        let room_id = self.client_rooms.remove(&client_id)?;
+        let room = self.rooms.get_mut(&room_id)?;
+        room.player_left();
+
+        let clients = room.clients().clone();
+        let empty_room = if room.player_count() == 0 {
+            let room = self.rooms.remove(&room_id);
+            if let Some(code) = room.as_ref().and_then(|room| room.code()) {
+                self.private_rooms.remove(code);
+            }
+            room
+        } else {
+            None
+        };
+
+        Some((clients, empty_room))
+    }
 
-            self.clients.complete_round().await;
+    fn create_session(&mut self, client_id: ClientId) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SESSION_TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+
+        self.sessions.insert(token.clone(), client_id);
+        self.client_sessions.insert(client_id, token.clone());
+        token
+    }
+
+    fn end_session(&mut self, client_id: ClientId) {
+        if let Some(token) = self.client_sessions.remove(&client_id) {
+            self.sessions.remove(&token);
+        }
+        self.cancel_expiry(client_id);
+    }
+
+    fn cancel_expiry(&mut self, client_id: ClientId) {
+        if let Some(timer) = self.expiry_timers.remove(&client_id) {
+            timer.abort();
         }
     }
+
+    fn client_room(&self, client_id: ClientId) -> Option<ClientManagerHandle> {
+        let room_id = self.client_rooms.get(&client_id)?;
+        self.rooms.get(room_id).map(|room| room.clients().clone())
+    }
 }
 
 impl GameServerHandle {
@@ -178,41 +347,238 @@ impl GameServerHandle {
         ClientId::new(id)
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn open_connection(&self, addr: IpAddr) -> Result<Connection, JoinError> {
+        let limit = self.config.limits.connections_per_ip;
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(addr).or_default();
+        if limit > 0 && *count >= limit {
+            log::warn!("({}) too many connections from this address", addr);
+            return Err(JoinError::TooManyConnections);
+        }
+        *count += 1;
+
+        Ok(Connection {
+            addr,
+            connections: self.connections.clone(),
+        })
+    }
+
     pub async fn register_client(
         self: &Arc<Self>,
         client_id: ClientId,
         addr: IpAddr,
         name: String,
-    ) -> Option<mpsc::Receiver<SerializedServerMessage>> {
-        {
-            // let mut connected_ips = self.connected_ips.lock().unwrap();
-            // #[cfg(not(debug_assertions))]
-            // if connected_ips.contains(&addr) {
-            //     log::warn!("client with ip {} already connected", addr);
-            //     return None;
-            // }
-            // connected_ips.insert(addr);
-        }
-
+        room: RoomRequest,
+        spectate: bool,
+    ) -> Result<mpsc::Receiver<SerializedServerMessage>, JoinError> {
+        let name = names::validate(&name, &self.config.names).map_err(JoinError::InvalidName)?;
         let (msg_tx, msg_rx) = mpsc::channel(8);
 
-        self.clients
-            .add_client(Client::new(client_id, name, msg_tx))
+        let (room, code, token) = {
+            let mut server = self.server.lock().unwrap();
+            let (room, code) = server.join_room(client_id, room, spectate)?;
+            let token = server.create_session(client_id);
+            (room, code, token)
+        };
+
+        let _ = msg_tx
+            .send(ServerMessage::JoinedRoom { code, token }.into())
             .await;
+        let mut client = Client::new(client_id, name, addr, msg_tx);
+        client.spectator = spectate;
+        room.add_client(client).await;
+
+        Ok(msg_rx)
+    }
+
+    pub async fn resume_client(
+        &self,
+        token: &str,
+    ) -> Result<(ClientId, mpsc::Receiver<SerializedServerMessage>), JoinError> {
+        let (client_id, room) = {
+            let server = self.server.lock().unwrap();
+            let client_id = *server
+                .sessions
+                .get(token)
+                .ok_or(JoinError::SessionExpired)?;
+            let room = server
+                .client_room(client_id)
+                .ok_or(JoinError::SessionExpired)?;
+            (client_id, room)
+        };
+
+        let (msg_tx, msg_rx) = mpsc::channel(8);
+        if !room.reconnect_client(client_id, msg_tx).await {
+            return Err(JoinError::SessionExpired);
+        }
+        self.server.lock().unwrap().cancel_expiry(client_id);
+
+        Ok((client_id, msg_rx))
+    }
+
+    // keep the client's spot for a while so they can resume their session after a dropped connection
+    pub fn client_disconnected(self: &Arc<Self>, client_id: ClientId) {
+        let grace = self.config.room.reconnect_grace;
+        let server = self.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+
+            // any newer disconnect or resume would have aborted this timer, so the entry is still ours
+            let room = {
+                let mut server = server.server.lock().unwrap();
+                server.expiry_timers.remove(&client_id);
+                server.client_room(client_id)
+            };
+            let connected = match room {
+                Some(room) => room.is_connected(client_id).await,
+                None => false,
+            };
+            if connected {
+                return;
+            }
 
-        Some(msg_rx)
+            server.remove_client(client_id).await;
+            log::info!("({}) session expired", client_id);
+        });
+
+        // a newer disconnect restarts the grace period
+        let mut server = self.server.lock().unwrap();
+        server.cancel_expiry(client_id);
+        server.expiry_timers.insert(client_id, timer.abort_handle());
+    }
+
+    pub async fn remove_client(&self, client_id: ClientId) {
+        let left_room = {
+            let mut server = self.server.lock().unwrap();
+            server.end_session(client_id);
+            server.leave_room(client_id)
+        };
+        if let Some((room, empty_room)) = left_room {
+            room.remove_client(client_id).await;
+
+            if let Some(empty_room) = empty_room {
+                empty_room.shutdown().await;
+            }
+        }
+    }
+
+    fn room(&self, room_id: u32) -> Result<ClientManagerHandle, AdminError> {
+        let server = self.server.lock().unwrap();
+        let room = server
+            .rooms
+            .get(&RoomId::new(room_id))
+            .ok_or(AdminError::RoomNotFound)?;
+        Ok(room.clients().clone())
+    }
+
+    pub async fn rooms(&self) -> Vec<RoomInfo> {
+        let rooms: Vec<_> = {
+            let server = self.server.lock().unwrap();
+            let mut rooms: Vec<_> = server
+                .rooms
+                .values()
+                .map(|room| {
+                    (
+                        room.id(),
+                        room.code().map(str::to_string),
+                        room.in_round(),
+                        room.player_count(),
+                        room.clients().clone(),
+                    )
+                })
+                .collect();
+            rooms.sort_by_key(|(id, ..)| *id);
+            rooms
+        };
+
+        let mut infos = Vec::with_capacity(rooms.len());
+        for (id, code, in_round, player_count, clients) in rooms {
+            infos.push(RoomInfo {
+                id,
+                code,
+                in_round,
+                player_count,
+                status: clients.status().await,
+            });
+        }
+        infos
     }
 
-    pub async fn remove_client(&self, client_id: ClientId, addr: IpAddr) {
-        self.clients.remove_client(client_id).await;
-        let mut connected_ips = self.connected_ips.lock().unwrap();
-        connected_ips.remove(&addr);
+    // the client is told why it's being disconnected and can't resume its session afterwards
+    pub async fn kick_client(&self, client_id: ClientId) -> Result<(), AdminError> {
+        let room = self.server.lock().unwrap().client_room(client_id);
+        let room = room.ok_or(AdminError::ClientNotFound)?;
+        let addr = room
+            .kick_client(client_id)
+            .await
+            .ok_or(AdminError::ClientNotFound)?;
+
+        log::info!("({}, {}) client was kicked", client_id, addr);
+        self.remove_client(client_id).await;
+        Ok(())
+    }
+
+    pub async fn end_round(&self, room_id: u32) -> Result<(), AdminError> {
+        if !self.room(room_id)?.end_round().await {
+            return Err(AdminError::NotRacing);
+        }
+        log::info!("room {}: round ended by an admin", room_id);
+        Ok(())
+    }
+
+    // skips the vote and plays the map next, it has to be one of the maps in the pool
+    pub fn set_next_map(&self, room_id: u32, map_path: &str) -> Result<(), AdminError> {
+        let server = self.server.lock().unwrap();
+        let room = server
+            .rooms
+            .get(&RoomId::new(room_id))
+            .ok_or(AdminError::RoomNotFound)?;
+        let map = server
+            .maps
+            .maps()
+            .iter()
+            .find(|m| m.path == map_path)
+            .cloned()
+            .ok_or(AdminError::MapNotFound)?;
+        room.set_next_map(map);
+        Ok(())
+    }
+
+    // sends the message to every room, or only to one of them
+    pub async fn announce(&self, room_id: Option<u32>, message: String) -> Result<(), AdminError> {
+        let rooms = match room_id {
+            Some(room_id) => vec![self.room(room_id)?],
+            None => {
+                let server = self.server.lock().unwrap();
+                server.rooms.values().map(|r| r.clients().clone()).collect()
+            }
+        };
+
+        log::info!("announcing to {} rooms: {}", rooms.len(), message);
+        for room in rooms {
+            room.announce(message.clone()).await;
+        }
+        Ok(())
+    }
+
+    pub async fn measured_rtt(&self, client_id: ClientId, rtt: Duration) {
+        let room = self.server.lock().unwrap().client_room(client_id);
+        if let Some(room) = room {
+            room.measured_rtt(client_id, rtt).await;
+        }
     }
 
     pub async fn handle_client_message(self: &Arc<Self>, client_id: ClientId, msg: ClientMessage) {
         // if !matches!(msg, ClientMessage::PlayerUpdate(_)) {
         //     log::info!("received message from client {}: {:?}", client_id, msg);
         // }
-        self.clients.handle_client_message(client_id, msg).await;
+        let room = self.server.lock().unwrap().client_room(client_id);
+        if let Some(room) = room {
+            room.handle_client_message(client_id, msg).await;
+        }
     }
 }
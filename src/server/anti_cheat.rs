@@ -0,0 +1,358 @@
+use common::{
+    PlayerState,
+    physics::{BOOST_DURATION, MAX_JUMP_HEIGHT, PhysicsMap, max_speed},
+};
+use std::time::Instant;
+
+use crate::config::AntiCheatConfig;
+
+// how far a kart may move at once, in seconds of driving at full speed. updates held back by
+// the network arrive in bursts
+const BURST: f32 = 0.5;
+// a boosted or bumped kart takes a while to slow back down
+const BOOST_SLOWDOWN: f32 = 1.5;
+// updates the player sent before getting a correction don't count against them
+const CORRECTION_GRACE: f32 = 1.0;
+// rounding makes landed karts end up just below the ground
+const JUMP_TOLERANCE: f32 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // nan or infinite values
+    Invalid,
+    TooFast,
+    ThroughWall,
+    Velocity,
+    JumpHeight,
+}
+
+impl Violation {
+    fn score(&self) -> f32 {
+        match self {
+            Violation::Invalid => 10.0,
+            Violation::ThroughWall => 3.0,
+            Violation::TooFast => 2.0,
+            Violation::Velocity | Violation::JumpHeight => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Verdict {
+    Accepted(PlayerState),
+    // the update was changed to something plausible. the player has to be corrected
+    Corrected(PlayerState, Violation),
+    // changed while the player is still catching up with the last correction
+    Adjusted(PlayerState),
+    Kick(Violation),
+}
+
+// checks the updates of one player against the ones before
+#[derive(Debug)]
+pub struct MovementCheck {
+    last_update: Option<Instant>,
+    // world units the kart can still move
+    distance_budget: f32,
+    boost_time: Option<Instant>,
+    // speed a bump added on top of the usual maximum
+    bump: Option<(Instant, f32)>,
+    correction_time: Option<Instant>,
+
+    suspicion: f32,
+    suspicion_time: Instant,
+}
+
+impl MovementCheck {
+    pub fn new() -> Self {
+        Self {
+            last_update: None,
+            distance_budget: 0.0,
+            boost_time: None,
+            bump: None,
+            correction_time: None,
+
+            suspicion: 0.0,
+            suspicion_time: Instant::now(),
+        }
+    }
+
+    // the player starts a new race, the suspicion carries over
+    pub fn reset(&mut self) {
+        self.last_update = None;
+        self.boost_time = None;
+        self.bump = None;
+        self.correction_time = None;
+    }
+
+    pub fn boosted(&mut self) {
+        self.boost_time = Some(Instant::now());
+    }
+
+    // the kart got pushed, it may go `extra` faster than usual for a bit
+    pub fn bumped(&mut self, extra: f32) {
+        self.bump = Some((Instant::now(), extra.max(0.0)));
+    }
+
+    pub fn check(
+        &mut self,
+        old: &PlayerState,
+        mut new: PlayerState,
+        // the coin count the server has for the player, coins make karts faster
+        coins: u32,
+        physics: &PhysicsMap,
+        config: &AntiCheatConfig,
+    ) -> Verdict {
+        let now = Instant::now();
+        let boosting = self
+            .boost_time
+            .is_some_and(|t| (now - t).as_secs_f32() < BOOST_DURATION + BOOST_SLOWDOWN);
+        let bump_speed = self
+            .bump
+            .filter(|(t, _)| (now - *t).as_secs_f32() < BOOST_SLOWDOWN)
+            .map_or(0.0, |(_, extra)| extra);
+        let max_speed = (max_speed(coins, boosting) + bump_speed) * config.speed_tolerance;
+
+        let max_budget = max_speed * BURST;
+        self.distance_budget = match self.last_update {
+            Some(last) => {
+                (self.distance_budget + max_speed * (now - last).as_secs_f32()).min(max_budget)
+            }
+            None => max_budget,
+        };
+        self.last_update = Some(now);
+
+        let mut violation = None;
+        let finite = [
+            new.pos.x,
+            new.pos.y,
+            new.vel,
+            new.rot,
+            new.visual_rot,
+            new.jump_height,
+        ]
+        .iter()
+        .all(|v| v.is_finite());
+        if !finite {
+            new = old.clone();
+            violation = Some(Violation::Invalid);
+        }
+
+        if new.vel.abs() > max_speed {
+            new.vel = new.vel.clamp(-max_speed, max_speed);
+            violation = Some(Violation::Velocity);
+        }
+        if !(-JUMP_TOLERANCE..=MAX_JUMP_HEIGHT + JUMP_TOLERANCE).contains(&new.jump_height) {
+            new.jump_height = new.jump_height.clamp(0.0, MAX_JUMP_HEIGHT);
+            violation = Some(Violation::JumpHeight);
+        }
+
+        let distance = old.pos.distance(new.pos);
+        if distance > self.distance_budget {
+            new.pos = old.pos + (new.pos - old.pos) / distance * self.distance_budget;
+            violation = Some(Violation::TooFast);
+        }
+        self.distance_budget -= old.pos.distance(new.pos);
+
+        if physics.crosses_wall(old.pos, new.pos) {
+            new.pos = old.pos;
+            violation = Some(Violation::ThroughWall);
+        }
+
+        let Some(violation) = violation else {
+            return Verdict::Accepted(new);
+        };
+
+        let catching_up = self
+            .correction_time
+            .is_some_and(|t| (now - t).as_secs_f32() < CORRECTION_GRACE);
+        if catching_up {
+            return Verdict::Adjusted(new);
+        }
+
+        let elapsed = (now - self.suspicion_time).as_secs_f32();
+        self.suspicion =
+            (self.suspicion - elapsed * config.score_decay).max(0.0) + violation.score();
+        self.suspicion_time = now;
+        if self.suspicion >= config.kick_score {
+            return Verdict::Kick(violation);
+        }
+
+        self.correction_time = Some(now);
+        Verdict::Corrected(new, violation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        map::{Collider, Map},
+        types::*,
+    };
+    use std::time::Duration;
+
+    fn state(x: f32, vel: f32) -> PlayerState {
+        PlayerState {
+            pos: Vec2::new(x, 0.0),
+            vel,
+            ..Default::default()
+        }
+    }
+
+    // no decay, so the score only depends on the violations
+    fn config() -> AntiCheatConfig {
+        AntiCheatConfig {
+            score_decay: 0.0,
+            ..AntiCheatConfig::default()
+        }
+    }
+
+    // the kart may move this far in its first update
+    fn first_budget() -> f32 {
+        max_speed(0, false) * config().speed_tolerance * BURST
+    }
+
+    fn check(check: &mut MovementCheck, old: &PlayerState, new: PlayerState) -> Verdict {
+        check.check(old, new, 0, &PhysicsMap::default(), &config())
+    }
+
+    fn end_grace(check: &mut MovementCheck) {
+        if let Some(time) = &mut check.correction_time {
+            *time -= Duration::from_secs_f32(CORRECTION_GRACE * 2.0);
+        }
+    }
+
+    #[test]
+    fn accepts_plausible_updates() {
+        let mut movement = MovementCheck::new();
+        let old = state(0.0, 10.0);
+        let verdict = check(&mut movement, &old, state(1.0, 11.0));
+        assert!(matches!(verdict, Verdict::Accepted(s) if s.pos.x == 1.0 && s.vel == 11.0));
+    }
+
+    #[test]
+    fn corrects_implausible_updates() {
+        let old = state(0.0, 10.0);
+
+        let verdict = check(&mut MovementCheck::new(), &old, state(100.0, 10.0));
+        let Verdict::Corrected(new, Violation::TooFast) = verdict else {
+            panic!("expected a correction, got {:?}", verdict);
+        };
+        assert!((new.pos.x - first_budget()).abs() < 0.001);
+
+        let verdict = check(&mut MovementCheck::new(), &old, state(0.0, 1000.0));
+        assert!(matches!(
+            verdict,
+            Verdict::Corrected(_, Violation::Velocity)
+        ));
+
+        let mut jumping = state(0.0, 10.0);
+        jumping.jump_height = 1.0;
+        let verdict = check(&mut MovementCheck::new(), &old, jumping);
+        assert!(
+            matches!(verdict, Verdict::Corrected(s, Violation::JumpHeight) if s.jump_height == MAX_JUMP_HEIGHT)
+        );
+    }
+
+    #[test]
+    fn corrects_through_walls() {
+        let mut map = Map::default();
+        map.colliders.push(Collider {
+            shape: vec![Vec2::new(10.0, -100.0), Vec2::new(10.0, 100.0)],
+        });
+        let physics = PhysicsMap::new(&map);
+
+        let old = state(0.0, 10.0);
+        let verdict = MovementCheck::new().check(&old, state(2.0, 10.0), 0, &physics, &config());
+        assert!(
+            matches!(verdict, Verdict::Corrected(s, Violation::ThroughWall) if s.pos == old.pos)
+        );
+    }
+
+    #[test]
+    fn invalid_values_kick_right_away() {
+        let verdict = check(
+            &mut MovementCheck::new(),
+            &state(0.0, 0.0),
+            state(f32::NAN, 0.0),
+        );
+        assert!(matches!(verdict, Verdict::Kick(Violation::Invalid)));
+    }
+
+    #[test]
+    fn updates_sent_before_the_correction_arrived_are_only_adjusted() {
+        let mut movement = MovementCheck::new();
+        let old = state(0.0, 10.0);
+        assert!(matches!(
+            check(&mut movement, &old, state(0.0, 1000.0)),
+            Verdict::Corrected(..)
+        ));
+
+        // these don't add to the score
+        for _ in 0..20 {
+            assert!(matches!(
+                check(&mut movement, &old, state(0.0, 1000.0)),
+                Verdict::Adjusted(s) if s.vel < 1000.0
+            ));
+        }
+        assert_eq!(movement.suspicion, Violation::Velocity.score());
+
+        end_grace(&mut movement);
+        assert!(matches!(
+            check(&mut movement, &old, state(0.0, 1000.0)),
+            Verdict::Corrected(..)
+        ));
+    }
+
+    #[test]
+    fn kicks_once_the_score_is_reached() {
+        let mut movement = MovementCheck::new();
+        let old = state(0.0, 10.0);
+        let kick_after = (config().kick_score / Violation::TooFast.score()).ceil() as usize;
+
+        for _ in 1..kick_after {
+            let verdict = check(&mut movement, &old, state(100.0, 10.0));
+            assert!(matches!(verdict, Verdict::Corrected(_, Violation::TooFast)));
+            end_grace(&mut movement);
+        }
+        let verdict = check(&mut movement, &old, state(100.0, 10.0));
+        assert!(matches!(verdict, Verdict::Kick(Violation::TooFast)));
+    }
+
+    #[test]
+    fn score_decays() {
+        let config = AntiCheatConfig::default();
+        let mut movement = MovementCheck::new();
+        let old = state(0.0, 10.0);
+        let physics = PhysicsMap::default();
+
+        for _ in 0..20 {
+            let verdict = movement.check(&old, state(0.0, 1000.0), 0, &physics, &config);
+            assert!(matches!(verdict, Verdict::Corrected(..)));
+            end_grace(&mut movement);
+            // long enough for the last violation to be forgotten
+            movement.suspicion_time -=
+                Duration::from_secs_f32(Violation::Velocity.score() / config.score_decay);
+        }
+    }
+
+    #[test]
+    fn bumps_allow_extra_speed() {
+        let old = state(0.0, 10.0);
+        let fast = max_speed(0, false) * config().speed_tolerance + 5.0;
+
+        let mut movement = MovementCheck::new();
+        movement.bumped(6.0);
+        assert!(matches!(
+            check(&mut movement, &old, state(0.0, fast)),
+            Verdict::Accepted(_)
+        ));
+
+        let mut movement = MovementCheck::new();
+        movement.bumped(-6.0);
+        assert!(matches!(
+            check(&mut movement, &old, state(0.0, fast)),
+            Verdict::Corrected(_, Violation::Velocity)
+        ));
+    }
+}
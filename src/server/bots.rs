@@ -0,0 +1,204 @@
+use common::{
+    ItemKind, PickupKind, PlayerState,
+    map::{Track, TrackPosition},
+    map_coord_to_world,
+    physics::{Bump, HIT_DURATION, KartInput, KartState, PhysicsMap},
+    types::*,
+    world_coord_to_map,
+};
+use rand::Rng;
+
+use super::game_state::GameState;
+use crate::{client::Client, config::BotsConfig};
+
+// how far ahead on the track bots aim, in map units
+const LOOKAHEAD: f32 = 60.0;
+// bots that barely move for this long back up for a moment
+const STUCK_TIME: f32 = 1.5;
+const REVERSE_TIME: f32 = 0.8;
+
+// drives a bot client with the same controls and physics a player has
+#[derive(Debug)]
+pub struct BotDriver {
+    config: BotsConfig,
+
+    // picked up from the client's start state on the first update
+    kart: Option<KartState>,
+    steer: f32,
+
+    lane_offset: f32,
+    target_lane_offset: f32,
+    lane_timer: f32,
+
+    hit_rotation: f32,
+    stuck_time: f32,
+    reverse_time: f32,
+}
+
+// what a bot does in a tick, handled like the messages of a real client
+#[derive(Debug)]
+pub struct BotActions {
+    pub state: PlayerState,
+    pub pick_ups: Vec<(PickupKind, usize)>,
+    pub use_item: Option<ItemKind>,
+}
+
+impl BotDriver {
+    pub fn new(config: &BotsConfig) -> Self {
+        Self {
+            config: config.clone(),
+
+            kart: None,
+            steer: 0.0,
+
+            lane_offset: 0.0,
+            target_lane_offset: 0.0,
+            lane_timer: 0.0,
+
+            hit_rotation: 0.0,
+            stuck_time: 0.0,
+            reverse_time: 0.0,
+        }
+    }
+
+    pub fn hit(&mut self) {
+        if let Some(kart) = &mut self.kart {
+            kart.hit();
+        }
+    }
+
+    pub fn bump(&mut self, bump: &Bump, physics: &PhysicsMap) {
+        if let Some(kart) = &mut self.kart {
+            kart.bump(bump, physics);
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        client: &Client,
+        place: usize,
+        game_state: &GameState,
+        dt: f32,
+    ) -> BotActions {
+        let mut rng = rand::thread_rng();
+        let state = &client.state;
+        let mut kart = self
+            .kart
+            .unwrap_or_else(|| KartState::from_player_state(state));
+
+        // wander around the middle of the track instead of following it exactly
+        self.lane_timer -= dt;
+        if self.lane_timer <= 0.0 {
+            self.lane_timer = rng.gen_range(1.0..3.0);
+            let noise = self.config.line_noise;
+            self.target_lane_offset = if noise > 0.0 {
+                rng.gen_range(-noise..=noise)
+            } else {
+                0.0
+            };
+        }
+        self.lane_offset = f32::lerp(self.lane_offset, self.target_lane_offset, dt.min(1.0));
+
+        let (target, dir) = point_ahead(
+            game_state.physics().track(),
+            client.track_pos,
+            world_coord_to_map(kart.pos),
+        );
+        let target = map_coord_to_world(target + dir.perp() * self.lane_offset);
+        let to_target = target - kart.pos;
+        let angle = angle_between(to_target.y.atan2(to_target.x).to_degrees(), kart.rot);
+
+        // it takes a moment to react, so the steering lags behind the track
+        let reaction = if self.config.reaction_time > 0.0 {
+            (dt / self.config.reaction_time).min(1.0)
+        } else {
+            1.0
+        };
+        self.steer = f32::lerp(self.steer, (angle / 30.0).clamp(-1.0, 1.0), reaction);
+
+        let mut throttle = if angle.abs() > 60.0 { 0.5 } else { 1.0 };
+        let mut steer = self.steer;
+
+        // back up after getting stuck on a wall
+        if self.reverse_time > 0.0 {
+            self.reverse_time -= dt;
+            throttle = -1.0;
+            steer = -steer;
+        } else if kart.velocity.y.abs() < 1.0 && !kart.is_hit() {
+            self.stuck_time += dt;
+            if self.stuck_time > STUCK_TIME {
+                self.stuck_time = 0.0;
+                self.reverse_time = REVERSE_TIME;
+            }
+        } else {
+            self.stuck_time = 0.0;
+        }
+
+        let use_chance = (self.config.item_usage * dt).min(1.0) as f64;
+        let use_item = client
+            .item
+            .filter(|_| !kart.is_hit() && rng.gen_bool(use_chance));
+        if use_item == Some(ItemKind::Boost) {
+            kart.boost();
+        }
+
+        let input = KartInput {
+            throttle,
+            steer,
+            // sharp corners are drifted through
+            drift: angle.abs() > 45.0 && throttle > 0.0,
+            coins: client.coins,
+            place,
+        };
+        kart = kart.step(&input, game_state.physics(), dt);
+        self.kart = Some(kart);
+
+        if kart.is_hit() {
+            // two spins over the time the bot is stunned
+            self.hit_rotation += dt * 360.0 * 2.0 / HIT_DURATION;
+        }
+
+        let visual_target = kart.rot + steer * 15.0 + self.hit_rotation;
+        BotActions {
+            state: PlayerState {
+                pos: kart.pos,
+                vel: kart.velocity.y,
+                rot: kart.rot,
+                visual_rot: f32::lerp(state.visual_rot, visual_target, dt * 5.0),
+                track_pos: kart.track_pos,
+                jump_height: kart.jump_height(),
+            },
+            pick_ups: game_state.pickups_near(kart.pos),
+            use_item,
+        }
+    }
+}
+
+// point in the middle of the track a bit ahead of the kart, and the direction of the track there.
+// positions are in map units
+fn point_ahead(track: &Track, mut track_pos: TrackPosition, pos: Vec2) -> (Vec2, Vec2) {
+    // only updates the progress along the current segment
+    track.calc_position(pos, pos, &mut track_pos);
+
+    let len = track.path.len();
+    let mut index = (track_pos.segment + len - 1) % len;
+    let mut segment = track.segment(index);
+    let mut remaining = LOOKAHEAD + segment.length() * track_pos.progress.clamp(0.0, 1.0);
+    for _ in 0..len {
+        if remaining <= segment.length() {
+            break;
+        }
+        remaining -= segment.length();
+        index = (index + 1) % len;
+        segment = track.segment(index);
+    }
+
+    let t = (remaining / segment.length().max(f32::EPSILON)).min(1.0);
+    let dir = (segment.end - segment.start).normalize_or_zero();
+    (segment.interpolate(t), dir)
+}
+
+// signed difference between two angles in degrees, between -180 and 180
+fn angle_between(a: f32, b: f32) -> f32 {
+    (a - b + 540.0).rem_euclid(360.0) - 180.0
+}
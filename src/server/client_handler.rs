@@ -1,13 +1,31 @@
-use common::{ClientId, ClientMessage, PickupKind, Placement, ServerMessage, map::Map};
-use std::collections::HashMap;
+use common::{
+    ActiveItemKind, ClientId, ClientMessage, INTERPOLATION_DELAY, ItemKind, LAP_COUNT,
+    MapCandidate, PickupKind, Placement, PlayerState, RaceResync, RoundInitParams, ServerMessage,
+    SpectateParams,
+    delta::{ItemId, RaceView},
+    map::Map,
+    physics::Bump,
+    replay::{Replay, ReplayEvent},
+    types::Vec2,
+};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Instant};
 use tokio::{
     sync::{mpsc, oneshot},
     task::{self, JoinHandle},
     time::{self, Duration},
 };
 
-use super::{SerializedServerMessage, game_state::GameState};
-use crate::client::Client;
+use super::{
+    SerializedServerMessage,
+    anti_cheat::{MovementCheck, Verdict},
+    bots::BotDriver,
+    game_state::GameState,
+    leaderboard::{Leaderboard, LeaderboardEntry},
+    names,
+};
+use crate::{client::Client, config::Config, metrics::METRICS};
 
 #[derive(Debug)]
 pub struct ClientManager {
@@ -18,14 +36,31 @@ pub struct ClientManager {
     loading_clients: Vec<Client>,
     clients: HashMap<ClientId, Client>,
     finished_clients: Vec<(Client, f32)>,
+    // bots only race in a single round and are in `clients` or `finished_clients` while they do
+    bots: HashMap<ClientId, BotDriver>,
+    next_bot_id: u32,
+    movement_checks: HashMap<ClientId, MovementCheck>,
 
     waiting_for_clients: Option<oneshot::Sender<()>>,
     loading_task: Option<LoadingTask>,
+    map_vote: Option<MapVote>,
 
+    race_start: Option<Instant>,
     end_round_task: Option<task::JoinHandle<()>>,
     force_end_round: bool,
 
+    // waiting clients watch the current round once it has started
+    map_path: String,
+    round_players: Option<Vec<(ClientId, String)>>,
+    recording: Option<Recording>,
+    // what every client watching the race has been sent so far, clients without one get a
+    // keyframe next
+    race_views: HashMap<ClientId, RaceView>,
+    race_ticks: u64,
+
     game_state: GameState,
+    leaderboard: Arc<Leaderboard>,
+    config: Arc<Config>,
 }
 
 #[derive(Debug)]
@@ -34,6 +69,21 @@ struct LoadingTask {
     result_tx: oneshot::Sender<Vec<(ClientId, String)>>,
 }
 
+#[derive(Debug)]
+struct Recording {
+    start: Instant,
+    replay: Replay,
+}
+
+#[derive(Debug)]
+struct MapVote {
+    candidates: Vec<MapCandidate>,
+    votes: HashMap<ClientId, usize>,
+    deadline: Instant,
+    timeout: JoinHandle<()>,
+    result_tx: oneshot::Sender<usize>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientManagerHandle {
     tx: mpsc::Sender<ClientManagerCommand>,
@@ -43,24 +93,52 @@ enum ClientManagerCommand {
     AwaitClient(oneshot::Sender<()>),
     AddClient(Client),
     RemoveClient(ClientId),
+    ReconnectClient {
+        id: ClientId,
+        tx: mpsc::Sender<SerializedServerMessage>,
+        result_tx: oneshot::Sender<bool>,
+    },
+    IsConnected {
+        id: ClientId,
+        result_tx: oneshot::Sender<bool>,
+    },
 
     HandleClientMessage(ClientId, ClientMessage),
-    SendServerMessage(SendTo, ServerMessage),
+    SendRaceMessage(ServerMessage),
+    MeasuredRtt(ClientId, Duration),
+
+    // admin controls
+    ListClients(oneshot::Sender<RoomStatus>),
+    KickClient {
+        id: ClientId,
+        result_tx: oneshot::Sender<Option<IpAddr>>,
+    },
+    EndRound(oneshot::Sender<bool>),
+    Announce(String),
 
+    VoteMap {
+        candidates: Vec<MapCandidate>,
+        duration: Duration,
+        result_tx: oneshot::Sender<usize>,
+    },
     LoadMap {
         map_path: String,
-        map: Map,
+        map: Arc<Map>,
         result_tx: oneshot::Sender<Vec<(ClientId, String)>>,
     },
+    StartRound(Vec<(ClientId, String)>),
+    StartRace,
     GameTick {
         race_time: f32,
         result_tx: oneshot::Sender<TickResult>,
     },
 
-    CompleteRound,
+    CompleteRound(oneshot::Sender<Option<Replay>>),
+    Shutdown,
 
     // internal
     LoadTimeout,
+    VoteTimeout,
     RaceTimeout,
     PickupRespawn {
         kind: PickupKind,
@@ -75,6 +153,55 @@ pub enum SendTo {
     InGameAll,
     InGameExcept(ClientId),
     InGameOnly(ClientId),
+    Spectators,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomStatus {
+    // the map of the current or last round
+    pub map: Option<String>,
+    pub clients: Vec<ClientInfo>,
+    pub active_items: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub name: String,
+    pub addr: IpAddr,
+    pub state: ClientState,
+    pub spectator: bool,
+    pub connected: bool,
+    pub bot: bool,
+    // seconds, measured with websocket pings
+    pub latency: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientState {
+    Waiting,
+    Loading,
+    InGame,
+    Finished,
+}
+
+impl ClientState {
+    pub const ALL: [ClientState; 4] = [
+        ClientState::Waiting,
+        ClientState::Loading,
+        ClientState::InGame,
+        ClientState::Finished,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ClientState::Waiting => "waiting",
+            ClientState::Loading => "loading",
+            ClientState::InGame => "in_game",
+            ClientState::Finished => "finished",
+        }
+    }
 }
 
 pub enum TickResult {
@@ -106,9 +233,77 @@ impl ClientManagerHandle {
             .unwrap();
     }
 
-    pub async fn send(&self, to: SendTo, msg: ServerMessage) {
+    // hands a new connection to a client that lost theirs, returns false if the client is gone
+    pub async fn reconnect_client(
+        &self,
+        id: ClientId,
+        tx: mpsc::Sender<SerializedServerMessage>,
+    ) -> bool {
+        let (result_tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::ReconnectClient { id, tx, result_tx })
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn is_connected(&self, id: ClientId) -> bool {
+        let (result_tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::IsConnected { id, result_tx })
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    // race events go to everyone in the race and everyone watching, and end up in the replay
+    pub async fn send_race(&self, msg: ServerMessage) {
         self.tx
-            .send(ClientManagerCommand::SendServerMessage(to, msg))
+            .send(ClientManagerCommand::SendRaceMessage(msg))
+            .await
+            .unwrap();
+    }
+
+    pub async fn measured_rtt(&self, id: ClientId, rtt: Duration) {
+        self.tx
+            .send(ClientManagerCommand::MeasuredRtt(id, rtt))
+            .await
+            .unwrap();
+    }
+
+    pub async fn status(&self) -> RoomStatus {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::ListClients(tx))
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    // tells the client they were kicked, returns their address if they are in this room.
+    // the client stays until it is removed like any other leaving client
+    pub async fn kick_client(&self, id: ClientId) -> Option<IpAddr> {
+        let (result_tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::KickClient { id, result_tx })
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    // ends the race as if it had timed out, returns false if there is no race running
+    pub async fn end_round(&self) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::EndRound(tx))
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn announce(&self, message: String) {
+        self.tx
+            .send(ClientManagerCommand::Announce(message))
             .await
             .unwrap();
     }
@@ -120,7 +315,22 @@ impl ClientManagerHandle {
             .unwrap();
     }
 
-    pub async fn load_map(&self, map_name: &str, map: Map) -> Vec<(ClientId, String)> {
+    // returns the index of the winning candidate
+    pub async fn vote_map(&self, candidates: Vec<MapCandidate>, duration: Duration) -> usize {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientManagerCommand::VoteMap {
+                candidates,
+                duration,
+                result_tx: tx,
+            })
+            .await
+            .unwrap();
+
+        rx.await.unwrap()
+    }
+
+    pub async fn load_map(&self, map_name: &str, map: Arc<Map>) -> Vec<(ClientId, String)> {
         let (tx, rx) = oneshot::channel();
         self.tx
             .send(ClientManagerCommand::LoadMap {
@@ -134,6 +344,17 @@ impl ClientManagerHandle {
         rx.await.unwrap()
     }
 
+    pub async fn start_round(&self, players: Vec<(ClientId, String)>) {
+        self.tx
+            .send(ClientManagerCommand::StartRound(players))
+            .await
+            .unwrap();
+    }
+
+    pub async fn start_race(&self) {
+        self.tx.send(ClientManagerCommand::StartRace).await.unwrap();
+    }
+
     pub async fn game_tick(&self, race_time: f32) -> TickResult {
         let (tx, rx) = oneshot::channel();
         self.tx
@@ -147,23 +368,31 @@ impl ClientManagerHandle {
         rx.await.unwrap()
     }
 
-    pub async fn complete_round(&self) {
+    // returns the recording of the round, if replays are enabled
+    pub async fn complete_round(&self) -> Option<Replay> {
+        let (tx, rx) = oneshot::channel();
         self.tx
-            .send(ClientManagerCommand::CompleteRound)
+            .send(ClientManagerCommand::CompleteRound(tx))
             .await
             .unwrap();
+        rx.await.unwrap()
     }
 
+    pub async fn shutdown(&self) {
+        self.tx.send(ClientManagerCommand::Shutdown).await.unwrap();
+    }
+
+    // the room might have been shut down while the timer was running, so errors are ignored here
     async fn pickup_respawn(&self, kind: PickupKind, index: usize) {
-        self.tx
+        let _ = self
+            .tx
             .send(ClientManagerCommand::PickupRespawn { kind, index })
-            .await
-            .unwrap();
+            .await;
     }
 }
 
 impl ClientManager {
-    pub fn new() -> ClientManagerHandle {
+    pub fn new(config: Arc<Config>, leaderboard: Arc<Leaderboard>) -> ClientManagerHandle {
         let (tx, rx) = mpsc::channel(128);
 
         let manager = Self {
@@ -174,13 +403,27 @@ impl ClientManager {
             loading_clients: Vec::new(),
             clients: HashMap::new(),
             finished_clients: Vec::new(),
+            bots: HashMap::new(),
+            // counts down so bot ids never run into the ones of real clients
+            next_bot_id: u32::MAX,
+            movement_checks: HashMap::new(),
 
             loading_task: None,
+            map_vote: None,
             waiting_for_clients: None,
+            race_start: None,
             end_round_task: None,
             force_end_round: false,
 
+            map_path: String::new(),
+            round_players: None,
+            recording: None,
+            race_views: HashMap::new(),
+            race_ticks: 0,
+
             game_state: GameState::default(),
+            leaderboard,
+            config,
         };
 
         tokio::spawn(manager.run());
@@ -198,7 +441,8 @@ impl ClientManager {
         while let Some(cmd) = self.rx.recv().await {
             match cmd {
                 ClientManagerCommand::AwaitClient(tx) => {
-                    if self.waiting_clients.is_empty() && self.loading_clients.is_empty() {
+                    // spectators alone don't start a round
+                    if self.waiting_racers().next().is_none() && self.loading_clients.is_empty() {
                         self.waiting_for_clients = Some(tx);
                     } else {
                         tx.send(()).unwrap();
@@ -206,12 +450,55 @@ impl ClientManager {
                 }
                 ClientManagerCommand::AddClient(client) => self.add_client(client).await,
                 ClientManagerCommand::RemoveClient(id) => self.remove_client(id).await,
+                ClientManagerCommand::ReconnectClient { id, tx, result_tx } => {
+                    let result = self.reconnect_client(id, tx).await;
+                    let _ = result_tx.send(result);
+                }
+                ClientManagerCommand::IsConnected { id, result_tx } => {
+                    let connected = self.find_client(id).is_some_and(|c| c.is_connected());
+                    let _ = result_tx.send(connected);
+                }
 
                 ClientManagerCommand::HandleClientMessage(id, msg) => {
                     self.handle_client_message(id, msg).await
                 }
-                ClientManagerCommand::SendServerMessage(to, msg) => self.send(to, msg).await,
+                ClientManagerCommand::SendRaceMessage(msg) => self.send_race(msg).await,
+                ClientManagerCommand::MeasuredRtt(id, rtt) => {
+                    if let Some(client) = self.find_client_mut(id) {
+                        client.update_rtt(rtt);
+                    }
+                }
+
+                ClientManagerCommand::ListClients(result_tx) => {
+                    let _ = result_tx.send(self.status());
+                }
+                ClientManagerCommand::KickClient { id, result_tx } => {
+                    let addr = match self.find_client(id) {
+                        Some(client) => {
+                            client.send(ServerMessage::Kicked).await;
+                            Some(client.addr())
+                        }
+                        None => None,
+                    };
+                    let _ = result_tx.send(addr);
+                }
+                ClientManagerCommand::EndRound(result_tx) => {
+                    let racing = self.race_start.is_some();
+                    if racing {
+                        self.force_end_round = true;
+                    }
+                    let _ = result_tx.send(racing);
+                }
+                ClientManagerCommand::Announce(message) => {
+                    self.send(SendTo::All, ServerMessage::Announcement(message))
+                        .await;
+                }
 
+                ClientManagerCommand::VoteMap {
+                    candidates,
+                    duration,
+                    result_tx,
+                } => self.start_vote(candidates, duration, result_tx).await,
                 ClientManagerCommand::LoadMap {
                     map_path,
                     map,
@@ -219,6 +506,11 @@ impl ClientManager {
                 } => {
                     self.load_map(map_path, map, result_tx).await;
                 }
+                ClientManagerCommand::StartRound(players) => self.start_round(players).await,
+                ClientManagerCommand::StartRace => {
+                    self.race_start = Some(Instant::now());
+                    self.send_race(ServerMessage::StartRace).await;
+                }
                 ClientManagerCommand::GameTick {
                     result_tx,
                     race_time,
@@ -227,7 +519,11 @@ impl ClientManager {
                     let _ = result_tx.send(result);
                 }
 
-                ClientManagerCommand::CompleteRound => self.complete_round().await,
+                ClientManagerCommand::CompleteRound(result_tx) => {
+                    let replay = self.complete_round().await;
+                    let _ = result_tx.send(replay);
+                }
+                ClientManagerCommand::Shutdown => break,
 
                 ClientManagerCommand::LoadTimeout => {
                     for client in &mut self.loading_clients {
@@ -235,6 +531,7 @@ impl ClientManager {
                             .send(SerializedServerMessage::new(ServerMessage::LoadedTooSlow))
                             .await;
                         log::warn!("client {} took too long to load", client.id());
+                        METRICS.load_timeouts.inc();
                         client.load_failures += 1;
                     }
                     self.loading_clients.retain(|c| c.load_failures < 3);
@@ -252,25 +549,42 @@ impl ClientManager {
                     }
                 }
 
+                ClientManagerCommand::VoteTimeout => self.finish_vote(),
                 ClientManagerCommand::RaceTimeout => self.force_end_round = true,
 
                 ClientManagerCommand::PickupRespawn { kind, index } => {
                     self.game_state.respawn_pickup(kind, index);
-                    self.send(
-                        SendTo::InGameAll,
-                        ServerMessage::PickUpStateChange {
-                            kind,
-                            index,
-                            state: true,
-                        },
-                    )
+                    self.send_race(ServerMessage::PickUpStateChange {
+                        kind,
+                        index,
+                        state: true,
+                    })
                     .await;
                 }
             }
         }
     }
 
-    async fn add_client(&mut self, client: Client) {
+    async fn add_client(&mut self, mut client: Client) {
+        let name = names::make_unique(
+            client.name(),
+            self.all_clients().map(Client::name),
+            self.config.names.max_length,
+        );
+        if name != client.name() {
+            log::info!(
+                "({}) name '{}' is taken, renamed to '{}'",
+                client.id(),
+                client.name(),
+                name
+            );
+            client.rename(name);
+        }
+        client
+            .send(ServerMessage::NameAccepted(client.name().to_string()))
+            .await;
+
+        let spectator = client.spectator;
         self.waiting_clients.push(client);
         self.send(
             SendTo::All,
@@ -280,9 +594,109 @@ impl ClientManager {
         )
         .await;
 
-        if let Some(tx) = self.waiting_for_clients.take() {
+        if let Some(tx) = self.waiting_for_clients.take_if(|_| !spectator) {
             tx.send(()).unwrap();
         }
+
+        // let people who join during a vote take part in it, or watch the race that is already running
+        let msg = self
+            .vote_message()
+            .filter(|_| !spectator)
+            .or_else(|| self.spectate_message());
+        if let (Some(msg), Some(client)) = (msg, self.waiting_clients.last()) {
+            client.send(msg).await;
+        }
+    }
+
+    async fn reconnect_client(
+        &mut self,
+        id: ClientId,
+        tx: mpsc::Sender<SerializedServerMessage>,
+    ) -> bool {
+        let Some(client) = self.find_client_mut(id) else {
+            return false;
+        };
+        if client.is_connected() {
+            // dropping the old sender closes the old connection
+            log::info!("({}) resumed session replaces an open connection", id);
+        }
+        client.reconnect(tx);
+        // updates sent while the connection was down never arrived
+        self.race_views.remove(&id);
+
+        let finished = self.finished_clients.iter().any(|(c, _)| c.id() == id);
+        let race = self
+            .clients
+            .get(&id)
+            .or_else(|| self.find_client(id).filter(|_| finished))
+            .map(|client| RaceResync {
+                race_time: self.race_start.map(|start| start.elapsed().as_secs_f32()),
+                state: client.state.clone(),
+                item: client.item,
+                coins: client.coins,
+                finished,
+                coin_states: self.game_state.coin_states().to_vec(),
+                item_box_states: self.game_state.item_box_states().to_vec(),
+            });
+        log::info!(
+            "client {} reconnected ({})",
+            id,
+            if race.is_some() {
+                "in race"
+            } else {
+                "in lobby"
+            }
+        );
+
+        let Some(client) = self.find_client(id) else {
+            return false;
+        };
+        client.send(ServerMessage::Resync { race }).await;
+        client
+            .send(ServerMessage::PlayerCountChanged {
+                count: self.waiting_clients.len() + self.clients.len(),
+            })
+            .await;
+
+        let in_lobby = self.waiting_clients.iter().any(|c| c.id() == id);
+        let msg = self
+            .vote_message()
+            .filter(|_| !client.spectator)
+            .or_else(|| self.spectate_message());
+        if let Some(msg) = msg.filter(|_| in_lobby) {
+            client.send(msg).await;
+        }
+
+        true
+    }
+
+    fn all_clients(&self) -> impl Iterator<Item = &Client> {
+        self.clients
+            .values()
+            .chain(self.waiting_clients.iter())
+            .chain(self.loading_clients.iter())
+            .chain(self.finished_clients.iter().map(|(c, _)| c))
+    }
+
+    fn find_client(&self, id: ClientId) -> Option<&Client> {
+        self.clients.get(&id).or_else(|| {
+            self.waiting_clients
+                .iter()
+                .chain(self.loading_clients.iter())
+                .chain(self.finished_clients.iter().map(|(c, _)| c))
+                .find(|c| c.id() == id)
+        })
+    }
+
+    fn find_client_mut(&mut self, id: ClientId) -> Option<&mut Client> {
+        if self.clients.contains_key(&id) {
+            return self.clients.get_mut(&id);
+        }
+        self.waiting_clients
+            .iter_mut()
+            .chain(self.loading_clients.iter_mut())
+            .chain(self.finished_clients.iter_mut().map(|(c, _)| c))
+            .find(|c| c.id() == id)
     }
 
     async fn remove_client(&mut self, id: ClientId) {
@@ -295,9 +709,15 @@ impl ClientManager {
         } else if let Some(pos) = self.finished_clients.iter().position(|(c, _)| c.id() == id) {
             self.finished_clients.remove(pos).0.disconnect();
         };
+        self.race_views.remove(&id);
+        self.movement_checks.remove(&id);
 
-        self.send(SendTo::InGameAll, ServerMessage::PlayerLeft(id))
-            .await;
+        if let Some(vote) = &mut self.map_vote {
+            vote.votes.remove(&id);
+        }
+        self.check_vote_complete();
+
+        self.send_race(ServerMessage::PlayerLeft(id)).await;
         self.send(
             SendTo::All,
             ServerMessage::PlayerCountChanged {
@@ -307,15 +727,161 @@ impl ClientManager {
         .await;
     }
 
+    fn status(&self) -> RoomStatus {
+        let info = |client: &Client, state| ClientInfo {
+            id: client.id(),
+            name: client.name().to_string(),
+            addr: client.addr(),
+            state,
+            spectator: client.spectator,
+            connected: client.is_connected(),
+            bot: client.is_bot(),
+            latency: client.latency(),
+        };
+
+        let clients = self
+            .waiting_clients
+            .iter()
+            .map(|c| info(c, ClientState::Waiting))
+            .chain(
+                self.loading_clients
+                    .iter()
+                    .map(|c| info(c, ClientState::Loading)),
+            )
+            .chain(self.clients.values().map(|c| info(c, ClientState::InGame)))
+            .chain(
+                self.finished_clients
+                    .iter()
+                    .map(|(c, _)| info(c, ClientState::Finished)),
+            )
+            .collect();
+
+        RoomStatus {
+            map: Some(self.map_path.clone()).filter(|map| !map.is_empty()),
+            clients,
+            active_items: self.game_state.active_item_count(),
+        }
+    }
+
+    fn waiting_racers(&self) -> impl Iterator<Item = &Client> {
+        self.waiting_clients.iter().filter(|c| !c.spectator)
+    }
+
+    fn spectate_message(&self) -> Option<ServerMessage> {
+        let players = self.round_players.as_ref()?;
+        let still_racing = |id: &ClientId| {
+            self.clients.contains_key(id)
+                || self.finished_clients.iter().any(|(c, _)| c.id() == *id)
+        };
+
+        Some(ServerMessage::Spectate {
+            params: SpectateParams {
+                map: self.map_path.clone(),
+                players: players
+                    .iter()
+                    .filter(|(id, _)| still_racing(id))
+                    .cloned()
+                    .collect(),
+                race_time: self.race_start.map(|start| start.elapsed().as_secs_f32()),
+                coin_states: self.game_state.coin_states().to_vec(),
+                item_box_states: self.game_state.item_box_states().to_vec(),
+            },
+        })
+    }
+
+    fn vote_message(&self) -> Option<ServerMessage> {
+        self.map_vote.as_ref().map(|vote| ServerMessage::MapVote {
+            candidates: vote.candidates.clone(),
+            duration: vote
+                .deadline
+                .saturating_duration_since(Instant::now())
+                .as_secs_f32(),
+        })
+    }
+
+    async fn start_vote(
+        &mut self,
+        candidates: Vec<MapCandidate>,
+        duration: Duration,
+        result_tx: oneshot::Sender<usize>,
+    ) {
+        let msg = SerializedServerMessage::new(ServerMessage::MapVote {
+            candidates: candidates.clone(),
+            duration: duration.as_secs_f32(),
+        });
+        for client in self.waiting_racers() {
+            client.send(msg.clone()).await;
+        }
+
+        let handle = self.make_handle();
+        let timeout = task::spawn(async move {
+            time::sleep(duration).await;
+            let _ = handle.tx.send(ClientManagerCommand::VoteTimeout).await;
+        });
+
+        self.map_vote = Some(MapVote {
+            candidates,
+            votes: HashMap::new(),
+            deadline: Instant::now() + duration,
+            timeout,
+            result_tx,
+        });
+    }
+
+    // end the vote early once everyone has voted
+    fn check_vote_complete(&mut self) {
+        let voters = self.waiting_racers().count();
+        let complete = self
+            .map_vote
+            .as_ref()
+            .is_some_and(|vote| vote.votes.len() >= voters);
+        if complete {
+            self.finish_vote();
+        }
+    }
+
+    // the most voted map wins, ties are broken randomly and without any votes the first candidate is played
+    fn finish_vote(&mut self) {
+        let Some(vote) = self.map_vote.take() else {
+            return;
+        };
+        vote.timeout.abort();
+
+        let mut tally = vec![0; vote.candidates.len()];
+        for &candidate in vote.votes.values() {
+            tally[candidate] += 1;
+        }
+
+        let most_votes = tally.iter().copied().max().unwrap_or(0);
+        let winners: Vec<_> = (0..tally.len())
+            .filter(|&i| tally[i] == most_votes)
+            .collect();
+        let winner = if most_votes == 0 {
+            0
+        } else {
+            *winners.choose(&mut rand::thread_rng()).unwrap()
+        };
+
+        log::info!("map vote finished with votes {:?}", tally);
+        let _ = vote.result_tx.send(winner);
+    }
+
     async fn load_map(
         &mut self,
         map_path: String,
-        map: Map,
+        map: Arc<Map>,
         result_tx: oneshot::Sender<Vec<(ClientId, String)>>,
     ) {
-        self.game_state = GameState::from_map(map);
+        let race = &self.config.race;
+        let max_rewind_ticks = (race.max_rewind.as_secs_f32() * race.tick_rate).ceil() as usize;
+        self.game_state = GameState::from_map(map, max_rewind_ticks);
+        self.map_path = map_path.clone();
 
-        self.loading_clients.extend(self.waiting_clients.drain(..));
+        let (spectators, racers): (Vec<_>, Vec<_>) =
+            self.waiting_clients.drain(..).partition(|c| c.spectator);
+        self.waiting_clients = spectators;
+        self.loading_clients.extend(racers);
+        self.add_bots();
 
         self.send(
             SendTo::LoadingAll,
@@ -325,14 +891,11 @@ impl ClientManager {
         )
         .await;
 
+        let load_timeout = self.config.race.load_timeout;
         let handle = self.make_handle();
         let handle = task::spawn(async move {
-            time::sleep(Duration::from_secs(10)).await;
-            handle
-                .tx
-                .send(ClientManagerCommand::LoadTimeout)
-                .await
-                .unwrap();
+            time::sleep(load_timeout).await;
+            let _ = handle.tx.send(ClientManagerCommand::LoadTimeout).await;
         });
 
         self.loading_task = Some(LoadingTask {
@@ -341,33 +904,205 @@ impl ClientManager {
         });
     }
 
-    async fn game_tick(&mut self, race_time: f32) -> TickResult {
-        let handle = self.make_handle();
-        self.game_state.tick(&mut self.clients, handle).await;
+    // fills the round up with bots, there is no point in racing only against bots though
+    fn add_bots(&mut self) {
+        let racers = self.loading_clients.len();
+        if racers == 0 {
+            return;
+        }
 
-        let race_update = ServerMessage::RaceUpdate {
-            race_time,
-            players: self
-                .clients
-                .values()
-                .chain(self.finished_clients.iter().map(|(c, _)| c))
-                .map(|client| (client.id(), client.state.clone()))
-                .collect(),
-            active_items: self.game_state.active_items(),
+        for _ in racers..self.config.bots.min_players {
+            let id = ClientId::new(self.next_bot_id);
+            self.next_bot_id -= 1;
+            let name = names::make_unique(
+                "bot",
+                self.all_clients().map(Client::name),
+                self.config.names.max_length,
+            );
+            log::info!("({}) bot '{}' joins the round", id, name);
+
+            self.clients.insert(id, Client::bot(id, name));
+            self.bots.insert(id, BotDriver::new(&self.config.bots));
+        }
+    }
+
+    // returns the state to go on with and whether the player has to be corrected, none if
+    // they were kicked
+    async fn check_movement(
+        &mut self,
+        id: ClientId,
+        state: PlayerState,
+    ) -> Option<(PlayerState, bool)> {
+        let client = self.clients.get(&id).or_else(|| {
+            self.finished_clients
+                .iter()
+                .map(|(c, _)| c)
+                .find(|c| c.id() == id)
+        });
+        let Some(client) = client.filter(|c| !c.is_bot() && self.config.anti_cheat.enabled) else {
+            return Some((state, false));
         };
 
-        self.send(SendTo::InGameAll, race_update).await;
+        let check = self
+            .movement_checks
+            .entry(id)
+            .or_insert_with(MovementCheck::new);
+        match check.check(
+            &client.state,
+            state,
+            client.coins,
+            self.game_state.physics(),
+            &self.config.anti_cheat,
+        ) {
+            Verdict::Accepted(state) | Verdict::Adjusted(state) => Some((state, false)),
+            Verdict::Corrected(state, violation) => {
+                log::warn!("({}) corrected implausible update: {:?}", id, violation);
+                METRICS.corrected_updates.inc();
+                Some((state, true))
+            }
+            Verdict::Kick(violation) => {
+                log::warn!(
+                    "({}) kicking client after too many implausible updates, last one: {:?}",
+                    id,
+                    violation
+                );
+                METRICS.cheat_kicks.inc();
+                client.send(ServerMessage::Kicked).await;
+                self.remove_client(id).await;
+                None
+            }
+        }
+    }
+
+    // bots send the same messages as a real client every tick
+    async fn drive_bots(&mut self) {
+        let dt = 1.0 / self.config.race.tick_rate;
+        let mut actions = Vec::new();
+        for (id, bot) in &mut self.bots {
+            if let Some(client) = self.clients.get(id) {
+                // bots further back get the same acceleration bonus as players
+                let place = 1 + self
+                    .clients
+                    .values()
+                    .filter(|c| c.track_pos > client.track_pos)
+                    .count();
+                actions.push((*id, bot.update(client, place, &self.game_state, dt)));
+            }
+        }
+
+        for (id, action) in actions {
+            self.handle_client_message(id, ClientMessage::PlayerUpdate(action.state))
+                .await;
+            for (kind, index) in action.pick_ups {
+                self.handle_client_message(id, ClientMessage::PickUp { kind, index })
+                    .await;
+            }
+            if let Some(item) = action.use_item {
+                self.handle_client_message(id, ClientMessage::UseItem(item))
+                    .await;
+            }
+        }
+    }
+
+    async fn start_round(&mut self, players: Vec<(ClientId, String)>) {
+        METRICS.rounds_started.inc();
+        for (i, (id, _)) in players.iter().enumerate() {
+            if let Some(client) = self.clients.get_mut(id) {
+                client.reset_race(self.game_state.start_state(i));
+            }
+            if let Some(check) = self.movement_checks.get_mut(id) {
+                check.reset();
+            }
+
+            self.send(
+                SendTo::InGameOnly(*id),
+                ServerMessage::StartRound {
+                    params: RoundInitParams {
+                        client_id: *id,
+                        start_pos: i,
+                        players: players.clone(),
+                    },
+                },
+            )
+            .await;
+        }
+
+        if self.config.replays.enabled {
+            self.recording = Some(Recording {
+                start: Instant::now(),
+                replay: Replay::new(self.map_path.clone(), players.clone()),
+            });
+        }
+
+        self.round_players = Some(players);
+        self.race_views.clear();
+        self.race_ticks = 0;
+        if let Some(msg) = self.spectate_message() {
+            self.send(SendTo::Spectators, msg).await;
+        }
+    }
+
+    async fn game_tick(&mut self, race_time: f32) -> TickResult {
+        self.drive_bots().await;
+
+        let handle = self.make_handle();
+        let rewind_ticks = self.rewind_ticks();
+        let dt = 1.0 / self.config.race.tick_rate;
+        let events = self
+            .game_state
+            .tick(&mut self.clients, handle, &rewind_ticks, dt)
+            .await;
+        for (id, bump) in events.bumps {
+            self.bump(id, bump).await;
+        }
+        // before the update that no longer has the items in it
+        for pos in events.item_collisions {
+            self.send_race(ServerMessage::ItemsCollided { pos }).await;
+        }
 
-        if self.clients.is_empty() || self.force_end_round {
+        let (players, coins) = self
+            .clients
+            .values()
+            .chain(self.finished_clients.iter().map(|(c, _)| c))
+            .map(|client| {
+                (
+                    (client.id(), client.state.clone()),
+                    (client.id(), client.coins),
+                )
+            })
+            .unzip();
+        self.send_race_update(race_time, players, coins, self.game_state.active_items())
+            .await;
+
+        // nobody is waiting for the bots to finish
+        let racing = self.clients.values().any(|c| !c.is_bot());
+        if !racing || self.force_end_round {
             TickResult::RaceOver
         } else {
             TickResult::NoChange
         }
     }
 
-    async fn complete_round(&mut self) {
+    // how far back the karts were that each player sees right now: updates take their latency
+    // to arrive and other karts are shown a bit in the past. bots see the race as it is
+    fn rewind_ticks(&self) -> HashMap<ClientId, u64> {
+        let race = &self.config.race;
+        let max_rewind = race.max_rewind.as_secs_f32();
+        self.clients
+            .values()
+            .chain(self.finished_clients.iter().map(|(c, _)| c))
+            .filter(|c| !c.is_bot())
+            .map(|c| {
+                let rewind = (c.latency() + INTERPOLATION_DELAY).min(max_rewind);
+                (c.id(), (rewind * race.tick_rate).round() as u64)
+            })
+            .collect()
+    }
+
+    async fn complete_round(&mut self) -> Option<Replay> {
         self.end_round_task.take().map(|t| t.abort());
         self.force_end_round = false;
+        self.race_start = None;
 
         let placements: Vec<_> = self
             .finished_clients
@@ -383,14 +1118,26 @@ impl ClientManager {
             .collect();
 
         log::info!("round completed with placements\n {:#?}", placements);
+        METRICS.rounds_completed.inc();
 
-        self.send(SendTo::InGameAll, ServerMessage::EndRound { placements })
-            .await;
+        let record = self.save_finishes().await;
+        self.send_race(ServerMessage::EndRound {
+            placements,
+            record: record.map(|entry| entry.race_record()),
+        })
+        .await;
+        self.round_players = None;
+
+        self.clients.retain(|_, c| !c.is_bot());
+        self.finished_clients.retain(|(c, _)| !c.is_bot());
+        self.bots.clear();
 
         self.waiting_clients
             .extend(self.clients.drain().map(|(_, c)| c));
         self.waiting_clients
             .extend(self.finished_clients.drain(..).map(|(c, _)| c));
+
+        self.recording.take().map(|recording| recording.replay)
     }
 
     async fn handle_client_message(&mut self, id: ClientId, message: ClientMessage) {
@@ -416,74 +1163,351 @@ impl ClientManager {
                 }
             }
 
+            ClientMessage::VoteMap(candidate) => {
+                let Some(vote) = &mut self.map_vote else {
+                    return;
+                };
+                if candidate >= vote.candidates.len() {
+                    log::warn!("client {} voted for invalid map {}", id, candidate);
+                    return;
+                }
+                if self
+                    .waiting_clients
+                    .iter()
+                    .any(|c| c.id() == id && !c.spectator)
+                {
+                    vote.votes.insert(id, candidate);
+                    self.check_vote_complete();
+                }
+            }
+
             ClientMessage::PlayerUpdate(state) => {
+                let Some((state, correct)) = self.check_movement(id, state).await else {
+                    return;
+                };
+
                 if let Some(client) = self.clients.get_mut(&id) {
-                    client.state = state;
+                    let lap = client.track_pos.lap;
+                    self.game_state.update_player(client, state);
+                    if correct {
+                        client
+                            .send(ServerMessage::Correction(client.state.clone()))
+                            .await;
+                    }
+
+                    // the first time over the line only starts the first lap
+                    if client.track_pos.lap > lap && lap > 0 {
+                        let race_time = self
+                            .race_start
+                            .map(|start| start.elapsed().as_secs_f32())
+                            .unwrap_or_default();
+                        client.complete_lap(race_time);
+                    }
+
+                    if client.track_pos.lap > LAP_COUNT {
+                        self.finish_client(id).await;
+                    }
                 } else if let Some((client, _)) =
                     self.finished_clients.iter_mut().find(|(c, _)| c.id() == id)
                 {
-                    client.state = state;
+                    self.game_state.update_player(client, state);
+                    if correct {
+                        client
+                            .send(ServerMessage::Correction(client.state.clone()))
+                            .await;
+                    }
                 }
             }
 
-            ClientMessage::UseItem(kind) => {
-                if let Some(client) = self.clients.get(&id) {
-                    self.game_state.add_item(kind, client, &self.clients)
+            ClientMessage::UseItem(item) => {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    if client.item == Some(item) {
+                        client.item = None;
+
+                        if item == ItemKind::Boost {
+                            self.movement_checks
+                                .entry(id)
+                                .or_insert_with(MovementCheck::new)
+                                .boosted();
+                        }
+
+                        let active_item = match item {
+                            ItemKind::Boost => None,
+                            ItemKind::Banana => Some(ActiveItemKind::Banana),
+                            ItemKind::RedShell => Some(ActiveItemKind::RedShell { roll: 0.0 }),
+                            ItemKind::GreenShell => Some(ActiveItemKind::GreenShell { roll: 0.0 }),
+                        };
+
+                        if let Some(active_item) = active_item {
+                            let client = &self.clients[&id];
+                            self.game_state.add_item(active_item, client, &self.clients);
+                        }
+                    } else {
+                        log::warn!(
+                            "client {} tried to use {:?} while holding {:?}",
+                            id,
+                            item,
+                            client.item
+                        );
+                    }
                 }
             }
 
             ClientMessage::PickUp { kind, index } => {
-                let success = self.game_state.pickup(kind, index);
-
-                if success {
-                    self.send(
-                        SendTo::InGameAll,
-                        ServerMessage::PickUpStateChange {
+                let Some(client) = self.clients.get(&id) else {
+                    return;
+                };
+                let pos = client.state.pos;
+                let success = self.game_state.pickup(kind, index, pos);
+
+                // the client already hid the pickup, show it again if it's still there
+                if !success && self.game_state.is_pickup_active(kind, index) {
+                    log::debug!("({}) too far away to pick up {:?} {}", id, kind, index);
+                    METRICS.rejected_pickups.inc();
+                    client
+                        .send(ServerMessage::PickUpStateChange {
                             kind,
                             index,
-                            state: false,
-                        },
-                    )
+                            state: true,
+                        })
+                        .await;
+                }
+
+                if success {
+                    match kind {
+                        PickupKind::Coin => {
+                            if let Some(client) = self.clients.get_mut(&id) {
+                                client.pick_up_coin();
+                            }
+                        }
+                        PickupKind::ItemBox => self.roll_item(id).await,
+                    }
+                }
+
+                if success {
+                    self.send_race(ServerMessage::PickUpStateChange {
+                        kind,
+                        index,
+                        state: false,
+                    })
                     .await;
 
                     let handle = self.make_handle();
+                    let respawn = self.config.race.pickup_respawn;
                     task::spawn(async move {
-                        time::sleep(Duration::from_secs(1)).await;
+                        time::sleep(respawn).await;
                         handle.pickup_respawn(kind, index).await;
                     });
                 }
             }
 
-            ClientMessage::FinishRound { race_time } => {
-                if let Some(client) = self.clients.remove(&id) {
-                    self.finished_clients.push((client, race_time));
-                }
-
-                if self.end_round_task.is_none()
-                    && !self.clients.is_empty()
-                    && !self.force_end_round
-                {
-                    let handle = self.make_handle();
-                    let handle = task::spawn(async move {
-                        time::sleep(Duration::from_secs(60)).await;
-                        handle
-                            .tx
-                            .send(ClientManagerCommand::RaceTimeout)
-                            .await
-                            .unwrap();
-                    });
-                    self.end_round_task = Some(handle);
+            ClientMessage::FinishRound { .. } => {
+                // finishing is decided by the server side lap count in PlayerUpdate, the message
+                // usually just arrives before the update that crosses the line
+                if let Some(client) = self.clients.get(&id) {
+                    if client.track_pos.lap > LAP_COUNT {
+                        self.finish_client(id).await;
+                    } else if client.track_pos.lap < LAP_COUNT {
+                        log::warn!(
+                            "client {} claimed to finish the round on lap {}",
+                            id,
+                            client.track_pos.lap
+                        );
+                    }
                 }
             }
 
-            ClientMessage::Register { .. } => {
+            ClientMessage::Register { .. } | ClientMessage::Resume { .. } => {
                 log::warn!("client {id} tried to register again");
             }
         }
     }
 
-    async fn send(&self, to: SendTo, msg: ServerMessage) {
+    async fn roll_item(&mut self, id: ClientId) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        if client.item.is_some() {
+            return;
+        }
+
+        // everyone who already finished is ahead of the player
+        let place = self.finished_clients.len()
+            + self
+                .clients
+                .values()
+                .filter(|other| other.track_pos > client.track_pos)
+                .count();
+        let player_count = self.finished_clients.len() + self.clients.len();
+
+        let item = self.config.items.roll(place, player_count);
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.item = Some(item);
+        }
+
+        self.send(SendTo::InGameOnly(id), ServerMessage::ReceivedItem { item })
+            .await;
+    }
+
+    async fn finish_client(&mut self, id: ClientId) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+
+        let race_time = self
+            .race_start
+            .map(|start| start.elapsed().as_secs_f32())
+            .unwrap_or_default();
+        log::info!("client {} finished the round in {:.2}s", id, race_time);
+        self.finished_clients.push((client, race_time));
+
+        if self.end_round_task.is_none() && !self.clients.is_empty() && !self.force_end_round {
+            let finish_timeout = self.config.race.finish_timeout;
+            let handle = self.make_handle();
+            let handle = task::spawn(async move {
+                time::sleep(finish_timeout).await;
+                let _ = handle.tx.send(ClientManagerCommand::RaceTimeout).await;
+            });
+            self.end_round_task = Some(handle);
+        }
+    }
+
+    // adds everyone who finished to the leaderboard, returns the record for the map afterwards
+    async fn save_finishes(&self) -> Option<LeaderboardEntry> {
+        let finishes: Vec<_> = self
+            .finished_clients
+            .iter()
+            .filter(|(c, _)| !c.is_bot())
+            .map(|(c, finish_time)| {
+                LeaderboardEntry::new(
+                    self.map_path.clone(),
+                    c.name().to_string(),
+                    *finish_time,
+                    c.lap_times.clone(),
+                )
+            })
+            .collect();
+
+        let leaderboard = self.leaderboard.clone();
+        let map = self.map_path.clone();
+        task::spawn_blocking(move || {
+            if let Err(e) = leaderboard.add(finishes) {
+                log::error!("failed to save finishes to the leaderboard: {}", e);
+            }
+            leaderboard.record(&map)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    // race events are shown to the racers and everyone watching, serialized only once for both
+    async fn send_race(&mut self, msg: ServerMessage) {
+        let hit_bot = match &msg {
+            ServerMessage::HitByItem { player } => self.bots.get_mut(player),
+            _ => None,
+        };
+        if let Some(bot) = hit_bot {
+            bot.hit();
+        }
+
+        self.record(&msg);
+
         let msg = SerializedServerMessage::new(msg);
+        self.send(SendTo::InGameAll, msg.clone()).await;
+        self.send(SendTo::Spectators, msg).await;
+    }
+
+    async fn bump(&mut self, id: ClientId, bump: Bump) {
+        if let Some(check) = self.movement_checks.get_mut(&id) {
+            check.bumped(bump.speed_change);
+        }
+
+        if let Some(bot) = self.bots.get_mut(&id) {
+            bot.bump(&bump, self.game_state.physics());
+        } else if let Some(client) = self.clients.get(&id) {
+            client.send(ServerMessage::Bumped(bump)).await;
+        }
+    }
+
+    // replays get the full update, everyone watching only what changed since the last one
+    // they were sent. things far away from a player's kart are updated less often for them
+    async fn send_race_update(
+        &mut self,
+        race_time: f32,
+        players: Vec<(ClientId, PlayerState)>,
+        coins: Vec<(ClientId, u32)>,
+        active_items: Vec<(ItemId, common::ActiveItem)>,
+    ) {
+        self.record(&ServerMessage::RaceUpdate {
+            race_time,
+            players: players.clone(),
+            coins: coins.clone(),
+            active_items: active_items.iter().map(|(_, i)| i.clone()).collect(),
+        });
+
+        let race = &self.config.race;
+        let keyframe_ticks = (race.keyframe_interval.as_secs_f32() * race.tick_rate).max(1.0);
+        let keyframe = self.race_ticks.is_multiple_of(keyframe_ticks as u64);
+        let far_update = self
+            .race_ticks
+            .is_multiple_of(race.far_update_interval as u64);
+        self.race_ticks += 1;
+
+        let spectators = self
+            .waiting_clients
+            .iter()
+            .filter(|_| self.round_players.is_some());
+        let watching = self
+            .clients
+            .values()
+            .chain(self.finished_clients.iter().map(|(c, _)| c))
+            .map(|c| (c, Some(c.state.pos)))
+            // spectators can follow anyone, so they get everything
+            .chain(spectators.map(|c| (c, None)));
+
+        for (client, own_pos) in watching {
+            if client.is_bot() || !client.is_connected() {
+                continue;
+            }
+
+            let keyframe = keyframe || !self.race_views.contains_key(&client.id());
+            let view = self.race_views.entry(client.id()).or_default();
+            let include = |pos: Vec2| match own_pos {
+                Some(own_pos) => {
+                    far_update
+                        || race.interest_radius <= 0.0
+                        || own_pos.distance(pos) <= race.interest_radius
+                }
+                None => true,
+            };
+            let msg = view.encode(
+                race_time,
+                keyframe,
+                // players drive their own kart
+                players
+                    .iter()
+                    .filter(|(id, _)| *id != client.id())
+                    .map(|(id, state)| (*id, state)),
+                &coins,
+                &active_items,
+                include,
+            );
+            client.send(msg).await;
+        }
+    }
+
+    fn record(&mut self, msg: &ServerMessage) {
+        if let Some(recording) = &mut self.recording {
+            recording.replay.events.push(ReplayEvent {
+                time: recording.start.elapsed().as_secs_f32(),
+                message: msg.clone(),
+            });
+        }
+    }
+
+    async fn send(&self, to: SendTo, msg: impl Into<SerializedServerMessage>) {
+        let msg = msg.into();
         match to {
             SendTo::All => {
                 for client in self
@@ -491,6 +1515,7 @@ impl ClientManager {
                     .iter()
                     .chain(self.loading_clients.iter())
                     .chain(self.clients.values())
+                    .chain(self.finished_clients.iter().map(|(c, _)| c))
                 {
                     client.send(msg.clone()).await;
                 }
@@ -529,6 +1554,14 @@ impl ClientManager {
                     client.send(msg).await;
                 }
             }
+            SendTo::Spectators => {
+                if self.round_players.is_none() {
+                    return;
+                }
+                for client in self.waiting_clients.iter() {
+                    client.send(msg.clone()).await;
+                }
+            }
         }
     }
 }
@@ -1,34 +1,103 @@
 use common::{
-    ActiveItemKind, ClientId, MAP_SCALE, PickupKind, ServerMessage,
+    ActiveItemKind, ClientId, MAP_SCALE, PickupKind, PlayerState, ServerMessage,
+    delta::ItemId,
     map::{Map, TrackPosition},
     map_coord_to_world,
+    physics::{self, Bump, MAX_BOOST_SPEED, PhysicsMap},
     types::*,
     world_coord_to_map,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use parry2d::shape::Polyline;
 
 use crate::client::Client;
-use crate::server::client_handler::{ClientManagerHandle, SendTo};
-
-const SHELL_SPEED: f32 = 0.45;
+use crate::server::client_handler::ClientManagerHandle;
+
+// in world units per second
+const SHELL_SPEED: f32 = 27.0;
+// degrees per second
+const SHELL_ROLL_SPEED: f32 = 1200.0;
+// red shells home in once their target is less than this many seconds of shell travel ahead
+const HOMING_TIME: f32 = 1.0 / 15.0;
+// in world units, same as the client
+const PICKUP_RADIUS: f32 = 0.6;
+// the client checks pickups against where it is now, the server only knows where it was last
+// frame. at 30fps a boosted kart moves this far between frames
+const PICKUP_SLACK: f32 = MAX_BOOST_SPEED / 30.0;
+// seconds before the same two karts can bump into each other again
+const BUMP_COOLDOWN: f32 = 0.5;
+// items run into each other when they are closer than this, in world units
+const ITEM_COLLISION_DISTANCE: f32 = 12.0 / MAP_SCALE;
+// items of the same player don't take each other out right after being used, so shells fired
+// one after the other both make it
+const SAME_OWNER_GRACE: Duration = Duration::from_millis(500);
+
+// what happened during a tick that the client manager has to pass on
+#[derive(Debug, Default)]
+pub struct TickEvents {
+    // already applied to the karts on the server
+    pub bumps: Vec<(ClientId, Bump)>,
+    // where two items took each other out
+    pub item_collisions: Vec<Vec2>,
+}
 
 #[derive(Debug, Default)]
 pub struct GameState {
-    map: Map,
-    colliders: Vec<Polyline>,
+    map: Arc<Map>,
+    physics: PhysicsMap,
 
     active_items: Vec<ActiveItem>,
+    // items keep their id while they are on the track, so clients only need to be told once
+    // what kind they are
+    next_item_id: ItemId,
     coin_states: Vec<bool>,
     item_box_states: Vec<bool>,
+    // when two karts last bumped into each other
+    bump_times: HashMap<(ClientId, ClientId), Instant>,
+
+    tick: u64,
+    history: PositionHistory,
+}
+
+// where every player was during the last few ticks
+#[derive(Debug, Default)]
+struct PositionHistory {
+    positions: HashMap<ClientId, VecDeque<(u64, Vec2)>>,
+    length: usize,
+}
+
+impl PositionHistory {
+    fn record(&mut self, tick: u64, players: &HashMap<ClientId, Client>) {
+        self.positions.retain(|id, _| players.contains_key(id));
+        for (id, player) in players {
+            let history = self.positions.entry(*id).or_default();
+            history.push_back((tick, player.state.pos));
+            while history.len() > self.length {
+                history.pop_front();
+            }
+        }
+    }
+
+    // where the player was at the given tick, or as far back as the history goes
+    fn at(&self, id: ClientId, tick: u64) -> Option<Vec2> {
+        let history = self.positions.get(&id)?;
+        history
+            .iter()
+            .rev()
+            .find(|(t, _)| *t <= tick)
+            .or(history.front())
+            .map(|(_, pos)| *pos)
+    }
 }
 
 #[derive(Debug)]
 struct ActiveItem {
+    id: ItemId,
     pos: Vec2,
     rot: f32,
     owner: ClientId,
@@ -56,6 +125,7 @@ enum RedShellTarget {
 
 impl ActiveItem {
     fn new(
+        id: ItemId,
         owner: &Client,
         map: &Map,
         clients: &HashMap<ClientId, Client>,
@@ -143,6 +213,7 @@ impl ActiveItem {
         };
 
         Self {
+            id,
             pos: owner.state.pos,
             rot: owner.state.rot,
             owner: owner.id(),
@@ -154,27 +225,29 @@ impl ActiveItem {
     pub fn update(
         &mut self,
         map: &Map,
-        colliders: &Vec<Polyline>,
+        colliders: &[Polyline],
         clients: &HashMap<ClientId, Client>,
+        dt: f32,
     ) -> bool {
         use parry2d::{
             math::{Isometry, Vector},
             shape::Ball,
         };
 
+        let step = SHELL_SPEED * dt;
         match &mut self.state {
             ActiveItemState::GreenShell { roll, bounces: _ } => {
-                self.pos += Vec2::new(self.rot.to_radians().cos(), self.rot.to_radians().sin())
-                    * SHELL_SPEED;
-                *roll += 20.0;
+                self.pos +=
+                    Vec2::new(self.rot.to_radians().cos(), self.rot.to_radians().sin()) * step;
+                *roll += SHELL_ROLL_SPEED * dt;
             }
             ActiveItemState::RedShell { target, roll } => {
-                *roll += 20.0;
+                *roll += SHELL_ROLL_SPEED * dt;
                 match target {
                     RedShellTarget::None => {
                         self.pos +=
                             Vec2::new(self.rot.to_radians().cos(), self.rot.to_radians().sin())
-                                * SHELL_SPEED;
+                                * step;
                     }
                     RedShellTarget::Player {
                         target_id,
@@ -184,15 +257,17 @@ impl ActiveItem {
                     } => {
                         if let Some(target) = clients.get(target_id) {
                             let mut future_pos = *track_pos;
-                            map.track
-                                .advance_position(SHELL_SPEED * 4.0 * MAP_SCALE, &mut future_pos);
+                            map.track.advance_position(
+                                SHELL_SPEED * HOMING_TIME * MAP_SCALE,
+                                &mut future_pos,
+                            );
 
                             if target.state.track_pos < future_pos || *homing  {
                                 let direction = (target.state.pos - self.pos).normalize();
-                                self.pos += direction * SHELL_SPEED;
+                                self.pos += direction * step;
                                 *homing = true;
                             } else {
-                                let mut advance = (SHELL_SPEED / 2.0) * MAP_SCALE;
+                                let mut advance = (step / 2.0) * MAP_SCALE;
                                 if !*on_track {
                                     advance *= 0.75;
                                 }
@@ -201,12 +276,12 @@ impl ActiveItem {
                                 let target_pos = map_coord_to_world(target_pos);
 
                                 let direction = target_pos - self.pos;
-                                self.pos += direction.normalize() * SHELL_SPEED;
+                                self.pos += direction.normalize() * step;
 
                                 self.rot = direction.y.atan2(direction.x).to_degrees();
 
                                 let distance = (self.pos - target_pos).length();
-                                if distance < SHELL_SPEED {
+                                if distance < step {
                                     *on_track = true;
                                 }
                             }
@@ -258,93 +333,169 @@ impl ActiveItem {
         false
     }
 
-    pub fn check_collision(&self, player: &Client) -> bool {
+    // shells destroy any item they run into, bananas only lie around
+    fn collides_with(&self, other: &ActiveItem, now: Instant) -> bool {
+        let is_shell = |item: &ActiveItem| !matches!(item.state, ActiveItemState::Banana);
+        if !is_shell(self) && !is_shell(other) {
+            return false;
+        }
+        if self.owner == other.owner
+            && now - self.spawn_time.max(other.spawn_time) < SAME_OWNER_GRACE
+        {
+            return false;
+        }
+
+        self.pos.distance(other.pos) < ITEM_COLLISION_DISTANCE
+    }
+
+    pub fn check_collision(&self, player: ClientId, player_pos: Vec2) -> bool {
         let now = Instant::now();
-        if self.owner == player.id() && now - self.spawn_time < Duration::from_millis(200) {
+        if self.owner == player && now - self.spawn_time < Duration::from_millis(200) {
             return false;
         }
 
-        let distance = (self.pos - player.state.pos).length();
+        let distance = (self.pos - player_pos).length();
         distance < 0.5
     }
 }
 
 impl GameState {
-    pub fn active_items(&self) -> Vec<common::ActiveItem> {
+    pub fn active_item_count(&self) -> usize {
+        self.active_items.len()
+    }
+
+    pub fn active_items(&self) -> Vec<(ItemId, common::ActiveItem)> {
         self.active_items
             .iter()
-            .map(|item| common::ActiveItem {
-                pos: item.pos,
-                rot: item.rot,
-                kind: match item.state {
-                    ActiveItemState::GreenShell { roll, .. } => {
-                        common::ActiveItemKind::GreenShell { roll }
-                    }
-                    ActiveItemState::RedShell { roll, .. } => {
-                        common::ActiveItemKind::RedShell { roll }
-                    }
-                    ActiveItemState::Banana => common::ActiveItemKind::Banana,
-                },
+            .map(|item| {
+                let active_item = common::ActiveItem {
+                    pos: item.pos,
+                    rot: item.rot,
+                    kind: match item.state {
+                        ActiveItemState::GreenShell { roll, .. } => {
+                            common::ActiveItemKind::GreenShell { roll }
+                        }
+                        ActiveItemState::RedShell { roll, .. } => {
+                            common::ActiveItemKind::RedShell { roll }
+                        }
+                        ActiveItemState::Banana => common::ActiveItemKind::Banana,
+                    },
+                };
+                (item.id, active_item)
             })
             .collect()
     }
 
-    pub fn from_map(map: Map) -> Self {
+    // `history_length` is how many ticks item hits can be rewound
+    pub fn from_map(map: Arc<Map>, history_length: usize) -> Self {
         let coin_states = vec![true; map.coins.len()];
         let item_box_states = vec![true; map.item_spawns.len()];
 
-        let colliders = map
-            .colliders
-            .iter()
-            .map(|c| {
-                let points = c
-                    .shape
-                    .iter()
-                    .map(|p| nalgebra::Point2::new(p.x, p.y))
-                    .chain(std::iter::once(nalgebra::Point2::new(
-                        c.shape[0].x,
-                        c.shape[0].y,
-                    )))
-                    .collect();
-                Polyline::new(points, None)
-            })
-            .collect();
-
         Self {
+            physics: PhysicsMap::new(&map),
             map,
-            colliders,
 
             active_items: Vec::new(),
+            next_item_id: 0,
             coin_states,
             item_box_states,
+            bump_times: HashMap::new(),
+
+            tick: 0,
+            history: PositionHistory {
+                positions: HashMap::new(),
+                length: history_length + 1,
+            },
+        }
+    }
+
+    pub fn physics(&self) -> &PhysicsMap {
+        &self.physics
+    }
+
+    // coins and item boxes that can be picked up from the given position
+    pub fn pickups_near(&self, pos: Vec2) -> Vec<(PickupKind, usize)> {
+        let near = |kind, positions: &[Vec2], states: &[bool]| {
+            positions
+                .iter()
+                .zip(states)
+                .enumerate()
+                .filter(|(_, (p, state))| {
+                    **state && map_coord_to_world(**p).distance(pos) < PICKUP_RADIUS
+                })
+                .map(|(i, _)| (kind, i))
+                .collect::<Vec<_>>()
+        };
+
+        let mut pickups = near(PickupKind::Coin, &self.map.coins, &self.coin_states);
+        pickups.extend(near(
+            PickupKind::ItemBox,
+            &self.map.item_spawns,
+            &self.item_box_states,
+        ));
+        pickups
+    }
+
+    pub fn start_state(&self, start_pos: usize) -> PlayerState {
+        let (pos, rot) = self.map.track.iter_starts().nth(start_pos).unwrap();
+        PlayerState {
+            pos: map_coord_to_world(pos),
+            rot,
+            visual_rot: rot,
+            ..Default::default()
         }
     }
 
+    // runs the lap counting on the server so the client's own track position is never trusted
+    pub fn update_player(&self, client: &mut Client, mut state: PlayerState) {
+        let old_pos = world_coord_to_map(client.state.pos);
+        let new_pos = world_coord_to_map(state.pos);
+        self.map
+            .track
+            .calc_position(old_pos, new_pos, &mut client.track_pos);
+
+        state.track_pos = client.track_pos;
+        client.state = state;
+    }
+
     pub fn add_item(
         &mut self,
         kind: ActiveItemKind,
         owner: &Client,
         clients: &HashMap<ClientId, Client>,
     ) {
-        let item = ActiveItem::new(owner, &self.map, clients, kind);
+        let item = ActiveItem::new(self.next_item_id, owner, &self.map, clients, kind);
+        self.next_item_id = self.next_item_id.wrapping_add(1);
         self.active_items.push(item);
     }
 
-    // returns true if the pickup was picked up
-    pub fn pickup(&mut self, kind: PickupKind, index: usize) -> bool {
-        let array = match kind {
-            PickupKind::Coin => &mut self.coin_states,
-            PickupKind::ItemBox => &mut self.item_box_states,
+    // returns true if the pickup was picked up. only karts at `pos` close enough to it can
+    pub fn pickup(&mut self, kind: PickupKind, index: usize, pos: Vec2) -> bool {
+        let (positions, array) = match kind {
+            PickupKind::Coin => (&self.map.coins, &mut self.coin_states),
+            PickupKind::ItemBox => (&self.map.item_spawns, &mut self.item_box_states),
         };
 
-        let mut success = false;
-        if index < array.len() {
-            success = array[index];
-            array[index] = false;
+        let (Some(pickup_pos), Some(state)) = (positions.get(index), array.get_mut(index)) else {
+            return false;
+        };
+        if map_coord_to_world(*pickup_pos).distance(pos) >= PICKUP_RADIUS + PICKUP_SLACK {
+            return false;
         }
+
+        let success = *state;
+        *state = false;
         success
     }
 
+    pub fn is_pickup_active(&self, kind: PickupKind, index: usize) -> bool {
+        let array = match kind {
+            PickupKind::Coin => &self.coin_states,
+            PickupKind::ItemBox => &self.item_box_states,
+        };
+        array.get(index).copied().unwrap_or(false)
+    }
+
     pub fn respawn_pickup(&mut self, kind: PickupKind, index: usize) {
         let array = match kind {
             PickupKind::Coin => &mut self.coin_states,
@@ -356,73 +507,75 @@ impl GameState {
         }
     }
 
+    pub fn coin_states(&self) -> &[bool] {
+        &self.coin_states
+    }
+
+    pub fn item_box_states(&self) -> &[bool] {
+        &self.item_box_states
+    }
+
+    // items hit the karts where their owner saw them, `rewind_ticks` back for every owner
     pub async fn tick(
         &mut self,
         players: &mut HashMap<ClientId, Client>,
         client_handler: ClientManagerHandle,
-    ) {
-        // use parry2d::{
-        //     math::{Isometry, Vector},
-        //     shape::Ball,
-        // };
-        // let player_collider = Ball::new((4.0 / MAP_SCALE) * 2.0);
-        //
-        // for (i, player) in players.values().enumerate() {
-        //     let player_pos =
-        //         Isometry::new(Vector::new(player.state.pos.x, player.state.pos.y), 0.0);
-        //     for other in players.values().skip(i + 1) {
-        //         let other_pos =
-        //             Isometry::new(Vector::new(other.state.pos.x, other.state.pos.y), 0.0);
-        //         if let Ok(Some(contact)) = parry2d::query::contact(
-        //             &player_pos,
-        //             &player_collider,
-        //             &other_pos,
-        //             &player_collider,
-        //             nalgebra::zero(),
-        //         ) {
-        //             client_handler
-        //                 .send(
-        //                     SendTo::InGameOnly(player.id()),
-        //                     ServerMessage::PlayerCollision {
-        //                         depth: -contact.dist / 2.0,
-        //                         normal: Vec2::new(contact.normal2.x, contact.normal2.y),
-        //
-        //                         other_velocity: other.state.vel,
-        //                         other_rotation: other.state.rot,
-        //                     },
-        //                 )
-        //                 .await;
-        //
-        //             client_handler
-        //                 .send(
-        //                     SendTo::InGameOnly(other.id()),
-        //                     ServerMessage::PlayerCollision {
-        //                         normal: Vec2::new(contact.normal1.x, contact.normal1.y),
-        //                         depth: -contact.dist / 2.0,
-        //
-        //                         other_velocity: player.state.vel,
-        //                         other_rotation: player.state.rot,
-        //                     },
-        //                 )
-        //                 .await;
-        //         }
-        //     }
-        // }
+        rewind_ticks: &HashMap<ClientId, u64>,
+        dt: f32,
+    ) -> TickEvents {
+        let mut events = TickEvents::default();
+
+        // the overlap lasts until the players got told about the bump, so the same two karts
+        // only bump once in a while
+        let now = Instant::now();
+        self.bump_times
+            .retain(|_, time| (now - *time).as_secs_f32() < BUMP_COOLDOWN);
+
+        let ids: Vec<_> = players.keys().copied().collect();
+        let mut bumps = Vec::new();
+        for (i, a) in ids.iter().enumerate() {
+            for b in &ids[i + 1..] {
+                let pair = (*a.min(b), *a.max(b));
+                if self.bump_times.contains_key(&pair) {
+                    continue;
+                }
+                if let Some((bump_a, bump_b)) = physics::bump(&players[a].state, &players[b].state)
+                {
+                    self.bump_times.insert(pair, now);
+                    bumps.push((*a, bump_a));
+                    bumps.push((*b, bump_b));
+                }
+            }
+        }
+        for (id, bump) in bumps {
+            if let Some(player) = players.get_mut(&id) {
+                let state = &mut player.state;
+                (state.pos, state.vel) = bump.apply(state.pos, state.vel, &self.physics);
+            }
+            events.bumps.push((id, bump));
+        }
+
+        self.tick += 1;
+        self.history.record(self.tick, players);
 
         for i in (0..self.active_items.len()).rev() {
             let item = &mut self.active_items[i];
 
-            let mut remove = item.update(&self.map, &self.colliders, &players);
+            let mut remove = item.update(&self.map, self.physics.colliders(), players, dt);
 
+            let rewind = rewind_ticks.get(&item.owner).copied().unwrap_or(0);
+            let tick = self.tick.saturating_sub(rewind);
             for player in players.values_mut() {
-                if item.check_collision(player) {
+                let pos = self
+                    .history
+                    .at(player.id(), tick)
+                    .unwrap_or(player.state.pos);
+                if item.check_collision(player.id(), pos) {
+                    player.hit();
                     client_handler
-                        .send(
-                            SendTo::InGameAll,
-                            ServerMessage::HitByItem {
-                                player: player.id(),
-                            },
-                        )
+                        .send_race(ServerMessage::HitByItem {
+                            player: player.id(),
+                        })
                         .await;
                     remove = true;
                 }
@@ -432,5 +585,28 @@ impl GameState {
                 self.active_items.swap_remove(i);
             }
         }
+
+        let mut destroyed = Vec::new();
+        for i in 0..self.active_items.len() {
+            for j in i + 1..self.active_items.len() {
+                if destroyed.contains(&i) || destroyed.contains(&j) {
+                    continue;
+                }
+
+                let (a, b) = (&self.active_items[i], &self.active_items[j]);
+                if a.collides_with(b, now) {
+                    let pos = (a.pos + b.pos) / 2.0;
+                    destroyed.extend([i, j]);
+                    events.item_collisions.push(pos);
+                }
+            }
+        }
+        // highest first, so swapping in the last item doesn't move one that still has to go
+        destroyed.sort_unstable();
+        for i in destroyed.into_iter().rev() {
+            self.active_items.swap_remove(i);
+        }
+
+        events
     }
 }
@@ -0,0 +1,108 @@
+use common::ItemKind;
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::Deserialize;
+
+// item chances depending on how far back a player is.
+// the first row is used for the leader and the last one for the back of the pack,
+// everyone in between gets spread evenly over the rows so the table works for any player count.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct ItemTable {
+    rows: Vec<ItemWeights>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ItemWeights {
+    pub green_shell: u32,
+    pub red_shell: u32,
+    pub banana: u32,
+    pub boost: u32,
+}
+
+impl ItemTable {
+    pub fn new(rows: Vec<ItemWeights>) -> Self {
+        assert!(!rows.is_empty(), "item table needs at least one row");
+        Self { rows }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rows.is_empty() {
+            return Err("item table needs at least one row".to_string());
+        }
+        for (i, row) in self.rows.iter().enumerate() {
+            if let Err(e) = WeightedIndex::new(row.weights()) {
+                return Err(format!("item table row {}: {}", i + 1, e));
+            }
+        }
+        Ok(())
+    }
+
+    fn weights(&self, place: usize, player_count: usize) -> &ItemWeights {
+        let row = if player_count <= 1 {
+            0
+        } else {
+            place.min(player_count - 1) * (self.rows.len() - 1) / (player_count - 1)
+        };
+        &self.rows[row]
+    }
+
+    // place is zero based, so 0 is the leader
+    pub fn roll(&self, place: usize, player_count: usize) -> ItemKind {
+        self.weights(place, player_count).roll()
+    }
+}
+
+impl Default for ItemTable {
+    fn default() -> Self {
+        Self::new(vec![
+            ItemWeights {
+                green_shell: 35,
+                red_shell: 10,
+                banana: 50,
+                boost: 5,
+            },
+            ItemWeights {
+                green_shell: 30,
+                red_shell: 30,
+                banana: 25,
+                boost: 15,
+            },
+            ItemWeights {
+                green_shell: 25,
+                red_shell: 40,
+                banana: 10,
+                boost: 25,
+            },
+            ItemWeights {
+                green_shell: 10,
+                red_shell: 40,
+                banana: 5,
+                boost: 45,
+            },
+        ])
+    }
+}
+
+impl ItemWeights {
+    const ITEMS: [ItemKind; 4] = [
+        ItemKind::GreenShell,
+        ItemKind::RedShell,
+        ItemKind::Banana,
+        ItemKind::Boost,
+    ];
+
+    fn weights(&self) -> [u32; 4] {
+        [self.green_shell, self.red_shell, self.banana, self.boost]
+    }
+
+    pub fn roll(&self) -> ItemKind {
+        match WeightedIndex::new(self.weights()) {
+            Ok(dist) => Self::ITEMS[dist.sample(&mut rand::thread_rng())],
+            Err(e) => {
+                log::error!("invalid item weights {:?}: {}", self, e);
+                ItemKind::Banana
+            }
+        }
+    }
+}
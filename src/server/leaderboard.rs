@@ -0,0 +1,151 @@
+use common::RaceRecord;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const LEADERBOARD_FILE: &str = "leaderboard.jsonl";
+
+// one finished race, stored as a line of json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub map: String,
+    pub name: String,
+    // seconds from the start of the race to crossing the finish line
+    pub time: f32,
+    // how long each lap took
+    pub laps: Vec<f32>,
+    // unix time the race was driven at
+    pub date: u64,
+}
+
+impl LeaderboardEntry {
+    pub fn new(map: String, name: String, time: f32, laps: Vec<f32>) -> Self {
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            map,
+            name,
+            time,
+            laps,
+            date,
+        }
+    }
+
+    pub fn race_record(&self) -> RaceRecord {
+        RaceRecord {
+            name: self.name.clone(),
+            time: self.time,
+            laps: self.laps.clone(),
+        }
+    }
+}
+
+// every finish is appended to a file in the data dir and kept in memory, grouped by map path
+#[derive(Debug)]
+pub struct Leaderboard {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, Vec<LeaderboardEntry>>>,
+}
+
+impl Leaderboard {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join(LEADERBOARD_FILE);
+
+        let mut entries: HashMap<String, Vec<LeaderboardEntry>> = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let entry: Result<LeaderboardEntry, _> = line
+                        .map_err(|e| e.to_string())
+                        .and_then(|line| serde_json::from_str(&line).map_err(|e| e.to_string()));
+                    match entry {
+                        Ok(entry) => entries.entry(entry.map.clone()).or_default().push(entry),
+                        Err(e) => log::warn!(
+                            "skipping invalid leaderboard entry in '{}' line {}: {}",
+                            path.display(),
+                            i + 1,
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("failed to read leaderboard '{}': {}", path.display(), e),
+        }
+
+        log::info!(
+            "loaded {} leaderboard entries for {} maps",
+            entries.values().map(Vec::len).sum::<usize>(),
+            entries.len()
+        );
+
+        Self {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    // blocks while writing, so call it from a blocking task
+    pub fn add(&self, new: Vec<LeaderboardEntry>) -> io::Result<()> {
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        // held while writing so finishes from different rooms don't end up interleaved
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        let mut lines = String::new();
+        for entry in &new {
+            lines += &serde_json::to_string(entry).map_err(io::Error::other)?;
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())?;
+
+        for entry in new {
+            entries.entry(entry.map.clone()).or_default().push(entry);
+        }
+        Ok(())
+    }
+
+    // the best finish of every player on the map, fastest first
+    pub fn top(&self, map: &str, limit: usize) -> Vec<LeaderboardEntry> {
+        let entries = self.entries.lock().unwrap();
+        let Some(entries) = entries.get(map) else {
+            return Vec::new();
+        };
+
+        let mut best: HashMap<&str, &LeaderboardEntry> = HashMap::new();
+        for entry in entries {
+            let current = best.entry(&entry.name).or_insert(entry);
+            if entry.time < current.time {
+                *current = entry;
+            }
+        }
+
+        let mut top: Vec<_> = best.into_values().cloned().collect();
+        top.sort_by(|a, b| a.time.total_cmp(&b.time));
+        top.truncate(limit);
+        top
+    }
+
+    pub fn record(&self, map: &str) -> Option<LeaderboardEntry> {
+        self.top(map, 1).pop()
+    }
+}
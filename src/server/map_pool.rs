@@ -0,0 +1,243 @@
+use common::{MapCandidate, map::Map};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    seq::SliceRandom,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPolicy {
+    Random,
+    // play every map once before any of them repeats
+    #[default]
+    Shuffle,
+    Sequential,
+    // weights are keyed by map path, maps without an entry get a weight of 1
+    Weighted(HashMap<String, u32>),
+}
+
+#[derive(Debug)]
+pub struct MapEntry {
+    // path the clients download the map from
+    pub path: String,
+    pub thumbnail: Option<String>,
+    pub map: Arc<Map>,
+}
+
+impl MapEntry {
+    pub fn candidate(&self) -> MapCandidate {
+        MapCandidate {
+            name: self.map.metadata.name.clone(),
+            author: self.map.metadata.author.clone(),
+            thumbnail: self.thumbnail.clone(),
+        }
+    }
+}
+
+// all playable maps in the maps directory, parsed and validated once per scan
+#[derive(Debug)]
+pub struct MapPool {
+    dir: PathBuf,
+    policy: RotationPolicy,
+    maps: RwLock<Arc<[Arc<MapEntry>]>>,
+}
+
+impl MapPool {
+    pub fn new(dir: impl Into<PathBuf>, policy: RotationPolicy) -> Self {
+        Self {
+            dir: dir.into(),
+            policy,
+            maps: RwLock::new(Arc::new([])),
+        }
+    }
+
+    pub async fn rescan(&self) {
+        let dir = self.dir.clone();
+        let maps = tokio::task::spawn_blocking(move || scan_dir(&dir))
+            .await
+            .unwrap();
+
+        if maps.is_empty() {
+            log::error!("no playable maps found in '{}'", self.dir.display());
+        } else {
+            log::info!(
+                "loaded {} maps: {:?}",
+                maps.len(),
+                maps.iter().map(|m| &m.path).collect::<Vec<_>>()
+            );
+        }
+
+        *self.maps.write().unwrap() = maps.into();
+    }
+
+    pub fn maps(&self) -> Arc<[Arc<MapEntry>]> {
+        self.maps.read().unwrap().clone()
+    }
+
+    pub fn rotation(&self) -> MapRotation {
+        MapRotation {
+            policy: self.policy.clone(),
+            last: None,
+            remaining: Vec::new(),
+        }
+    }
+}
+
+// tracks which maps a room has already played. maps are referred to by path so a rescan doesn't reset the rotation
+#[derive(Debug)]
+pub struct MapRotation {
+    policy: RotationPolicy,
+    last: Option<String>,
+    remaining: Vec<String>,
+}
+
+impl MapRotation {
+    pub fn next(&mut self, maps: &[Arc<MapEntry>]) -> Option<Arc<MapEntry>> {
+        if maps.is_empty() {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        let next = match &self.policy {
+            RotationPolicy::Random => maps.choose(&mut rng).cloned(),
+            RotationPolicy::Shuffle => {
+                // maps might have been removed by a rescan since the bag was filled
+                self.remaining
+                    .retain(|path| maps.iter().any(|m| &m.path == path));
+                if self.remaining.is_empty() {
+                    self.remaining = maps.iter().map(|m| m.path.clone()).collect();
+                    self.remaining.shuffle(&mut rng);
+
+                    // the bag is popped from the back, so keep the last map from coming up twice in a row
+                    if self.remaining.len() > 1 && self.remaining.last() == self.last.as_ref() {
+                        let last = self.remaining.len() - 1;
+                        self.remaining.swap(0, last);
+                    }
+                }
+
+                let path = self.remaining.pop()?;
+                maps.iter().find(|m| m.path == path).cloned()
+            }
+            RotationPolicy::Sequential => {
+                let mut sorted: Vec<_> = maps.iter().collect();
+                sorted.sort_by(|a, b| a.path.cmp(&b.path));
+                let next = self
+                    .last
+                    .as_ref()
+                    .and_then(|last| sorted.iter().position(|m| &m.path > last))
+                    .unwrap_or(0);
+                sorted.get(next).map(|m| (*m).clone())
+            }
+            RotationPolicy::Weighted(weights) => {
+                let weights = maps
+                    .iter()
+                    .map(|m| weights.get(&m.path).copied().unwrap_or(1));
+                match WeightedIndex::new(weights) {
+                    Ok(dist) => Some(maps[dist.sample(&mut rng)].clone()),
+                    Err(e) => {
+                        log::error!("invalid map weights: {}", e);
+                        maps.choose(&mut rng).cloned()
+                    }
+                }
+            }
+        };
+
+        self.last = next.as_ref().map(|m| m.path.clone());
+        next
+    }
+
+    // the map the rotation would pick comes first, the rest are random other maps
+    pub fn candidates(&mut self, maps: &[Arc<MapEntry>], count: usize) -> Vec<Arc<MapEntry>> {
+        let Some(first) = self.next(maps) else {
+            return Vec::new();
+        };
+
+        let others: Vec<_> = maps.iter().filter(|m| m.path != first.path).collect();
+        let mut candidates = vec![first];
+        candidates.extend(
+            others
+                .choose_multiple(&mut rand::thread_rng(), count.saturating_sub(1))
+                .map(|m| (*m).clone()),
+        );
+        candidates
+    }
+
+    // the vote might pick a different map than the rotation did
+    pub fn played(&mut self, map: &MapEntry) {
+        self.remaining.retain(|path| *path != map.path);
+        self.last = Some(map.path.clone());
+    }
+}
+
+fn scan_dir(dir: &Path) -> Vec<Arc<MapEntry>> {
+    let mut files = Vec::new();
+    find_map_files(dir, &mut files);
+    files.sort();
+
+    files
+        .into_iter()
+        .filter_map(|file| {
+            let map = match File::open(&file).map_err(Into::into).and_then(Map::load) {
+                Ok(map) => map,
+                Err(e) => {
+                    log::warn!("failed to load map '{}': {:?}", file.display(), e);
+                    return None;
+                }
+            };
+
+            if let Err(e) = map.validate() {
+                log::warn!("skipping invalid map '{}': {}", file.display(), e);
+                return None;
+            }
+
+            // fall back to the map.png next to the map file
+            let thumbnail = file
+                .parent()
+                .map(|parent| parent.join(map.metadata.thumbnail.as_deref().unwrap_or("map.png")))
+                .filter(|thumbnail| thumbnail.is_file())
+                .and_then(|thumbnail| url_path(dir, &thumbnail));
+
+            Some(Arc::new(MapEntry {
+                path: url_path(dir, &file)?,
+                thumbnail,
+                map: Arc::new(map),
+            }))
+        })
+        .collect()
+}
+
+// path below the /maps route for a file inside the maps directory
+fn url_path(dir: &Path, file: &Path) -> Option<String> {
+    let relative = file.strip_prefix(dir).ok()?;
+    let path = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .fold(String::from("maps"), |path, c| path + "/" + &c);
+    Some(path)
+}
+
+fn find_map_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("failed to read maps directory '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_map_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "smk") {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use common::NameRejection;
+use std::collections::HashSet;
+
+use crate::config::NamesConfig;
+
+// cleans up the name a player registered with and checks it against the config
+pub fn validate(name: &str, config: &NamesConfig) -> Result<String, NameRejection> {
+    let name = sanitize(name);
+
+    let length = name.chars().count();
+    if length < config.min_length {
+        return Err(NameRejection::TooShort {
+            min: config.min_length,
+        });
+    }
+    if length > config.max_length {
+        return Err(NameRejection::TooLong {
+            max: config.max_length,
+        });
+    }
+
+    // spaces, punctuation and case don't get around the deny list
+    let normalized = normalize(&name);
+    let denied = config
+        .deny_list
+        .iter()
+        .map(|word| normalize(word))
+        .any(|word| !word.is_empty() && normalized.contains(&word));
+    if denied {
+        return Err(NameRejection::NotAllowed);
+    }
+
+    Ok(name)
+}
+
+// appends a number to the name if someone else is already using it, still keeping it
+// within the length limit
+pub fn make_unique<'a>(
+    name: &str,
+    taken: impl IntoIterator<Item = &'a str>,
+    max_length: usize,
+) -> String {
+    let taken: HashSet<_> = taken.into_iter().map(str::to_lowercase).collect();
+    if !taken.contains(&name.to_lowercase()) {
+        return name.to_string();
+    }
+
+    (2..)
+        .map(|n| {
+            let suffix = format!(" {}", n);
+            let base: String = name
+                .chars()
+                .take(max_length.saturating_sub(suffix.len()))
+                .collect();
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|candidate| !taken.contains(&candidate.to_lowercase()))
+        .unwrap()
+}
+
+// drops control and invisible characters and collapses whitespace
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !is_invisible(*c))
+        .collect();
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// zero width, bidi override and other formatting characters that would let names
+// look identical or mess up how the text around them is shown
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{061C}'
+            | '\u{115F}'
+            | '\u{1160}'
+            | '\u{17B4}'
+            | '\u{17B5}'
+            | '\u{180B}'..='\u{180F}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{206F}'
+            | '\u{3164}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{FFA0}'
+            | '\u{FFF0}'..='\u{FFFB}'
+            | '\u{E0000}'..='\u{E007F}'
+    )
+}
+
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(deny_list: &[&str]) -> NamesConfig {
+        NamesConfig {
+            min_length: 2,
+            max_length: 16,
+            deny_list: deny_list.iter().map(|w| w.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn length_limits() {
+        let config = config(&[]);
+        assert!(matches!(
+            validate("a", &config),
+            Err(NameRejection::TooShort { min: 2 })
+        ));
+        assert!(matches!(
+            validate("abcdefghijklmnopq", &config),
+            Err(NameRejection::TooLong { max: 16 })
+        ));
+        assert_eq!(validate("ab", &config).unwrap(), "ab");
+        assert_eq!(
+            validate("abcdefghijklmnop", &config).unwrap(),
+            "abcdefghijklmnop"
+        );
+        // whitespace doesn't count towards the length
+        assert_eq!(validate("  a    b  ", &config).unwrap(), "a b");
+    }
+
+    #[test]
+    fn strips_invisible_characters() {
+        let config = config(&[]);
+        assert_eq!(validate("mi\u{200B}mi", &config).unwrap(), "mimi");
+        assert_eq!(validate("\u{202E}mimi\u{202C}", &config).unwrap(), "mimi");
+        assert_eq!(validate("mi\u{FEFF}mi\u{E0041}", &config).unwrap(), "mimi");
+        assert_eq!(validate("mi\tmi\n", &config).unwrap(), "mimi");
+        // nothing left once they are gone
+        assert!(matches!(
+            validate("\u{200B}\u{200D}\u{2060}", &config),
+            Err(NameRejection::TooShort { .. })
+        ));
+    }
+
+    #[test]
+    fn deny_list_is_normalized() {
+        let config = config(&["Bad Word"]);
+        for name in [
+            "badword",
+            "BADWORD",
+            "b.a.d w-o-r-d",
+            "xbadwordx",
+            "bad\u{200B}word",
+        ] {
+            assert!(
+                matches!(validate(name, &config), Err(NameRejection::NotAllowed)),
+                "{name}"
+            );
+        }
+        assert!(validate("bad wolf", &config).is_ok());
+
+        // entries without letters or digits don't reject everything
+        assert!(validate("mimi", &self::config(&["!!", ""])).is_ok());
+    }
+
+    #[test]
+    fn unique_names() {
+        assert_eq!(make_unique("mimi", ["kart"], 8), "mimi");
+        assert_eq!(make_unique("mimi", ["MIMI"], 8), "mimi 2");
+        assert_eq!(make_unique("mimi", ["mimi", "mimi 2"], 8), "mimi 3");
+    }
+
+    #[test]
+    fn unique_suffix_stays_within_max_length() {
+        let taken = ["abcdefgh", "abcdef 2"];
+        assert_eq!(make_unique("abcdefgh", taken, 8), "abcdef 3");
+
+        let mut taken: Vec<String> = vec!["abcdefgh".to_string()];
+        taken.extend((2..10).map(|n| format!("abcdef {}", n)));
+        let name = make_unique("abcdefgh", taken.iter().map(String::as_str), 8);
+        assert_eq!(name, "abcde 10");
+        assert!(name.chars().count() <= 8);
+
+        // no trailing space before the number
+        assert_eq!(make_unique("abcde fg", ["abcde fg"], 8), "abcde 2");
+    }
+}
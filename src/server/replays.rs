@@ -0,0 +1,96 @@
+use common::{PROTOCOL_VERSION, replay::Replay};
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::room::RoomId;
+use crate::config::ReplaysConfig;
+
+const REPLAY_EXTENSION: &str = "replay";
+// the version is a varint at the start of the file
+const VERSION_BYTES: u64 = 5;
+
+#[derive(Debug, Serialize)]
+pub struct ReplayEntry {
+    pub name: String,
+    // none if the file couldn't be read
+    pub version: Option<u32>,
+    // replays recorded on another protocol version can't be played by this version of the game
+    pub playable: bool,
+}
+
+// replays are named `<unix time>-room<id>-<map>.replay`, so sorting them by name sorts them by age
+pub fn save_replay(config: &ReplaysConfig, room: RoomId, replay: &Replay) -> io::Result<PathBuf> {
+    fs::create_dir_all(&config.dir)?;
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let map = Path::new(&replay.map)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let path = config.dir.join(format!(
+        "{}-room{}-{}.{}",
+        time, room, map, REPLAY_EXTENSION
+    ));
+
+    let bytes = replay.to_bytes().map_err(io::Error::other)?;
+    fs::write(&path, bytes)?;
+
+    if config.keep > 0 {
+        for old in list_replays(&config.dir).iter().skip(config.keep) {
+            if let Err(e) = fs::remove_file(config.dir.join(old)) {
+                log::warn!("failed to delete old replay '{}': {}", old, e);
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+// file names of all saved replays, newest first
+fn list_replays(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut replays: Vec<_> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == REPLAY_EXTENSION))
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect();
+    replays.sort_by(|a, b| b.cmp(a));
+    replays
+}
+
+// saved replays with the version they were recorded on, newest first
+pub fn replay_entries(dir: &Path) -> Vec<ReplayEntry> {
+    list_replays(dir)
+        .into_iter()
+        .map(|name| {
+            let version = read_version(&dir.join(&name));
+            ReplayEntry {
+                name,
+                version,
+                playable: version == Some(PROTOCOL_VERSION),
+            }
+        })
+        .collect()
+}
+
+fn read_version(path: &Path) -> Option<u32> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+        .ok()?
+        .take(VERSION_BYTES)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    Replay::peek_version(&bytes)
+}
@@ -0,0 +1,279 @@
+use common::{COUNTDOWN_DURATION, ServerMessage, replay::Replay};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+
+use super::{
+    client_handler::{ClientManager, ClientManagerHandle, TickResult},
+    leaderboard::Leaderboard,
+    map_pool::{MapEntry, MapPool, MapRotation},
+    replays::save_replay,
+};
+use crate::{config::Config, metrics::METRICS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct RoomId(u32);
+
+impl RoomId {
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+}
+
+impl std::fmt::Display for RoomId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Room {
+    id: RoomId,
+    clients: ClientManagerHandle,
+    in_round: Arc<AtomicBool>,
+    // set by the admins to skip the vote for the next round
+    next_map: Arc<Mutex<Option<Arc<MapEntry>>>>,
+    maps: Arc<MapPool>,
+    rotation: MapRotation,
+    config: Arc<Config>,
+}
+
+#[derive(Debug)]
+pub struct RoomHandle {
+    id: RoomId,
+    code: Option<String>,
+    clients: ClientManagerHandle,
+    in_round: Arc<AtomicBool>,
+    next_map: Arc<Mutex<Option<Arc<MapEntry>>>>,
+    task: JoinHandle<()>,
+    max_players: usize,
+
+    // only touched while holding the server lock, so joining and leaving can't race each other
+    player_count: usize,
+}
+
+impl Room {
+    // rooms with a code are private and only reachable by people who know it
+    pub fn new(
+        id: RoomId,
+        code: Option<String>,
+        maps: Arc<MapPool>,
+        leaderboard: Arc<Leaderboard>,
+        config: Arc<Config>,
+    ) -> RoomHandle {
+        let clients = ClientManager::new(config.clone(), leaderboard);
+        let max_players = config.room.max_players;
+        let in_round = Arc::new(AtomicBool::new(false));
+        let next_map = Arc::new(Mutex::new(None));
+
+        let room = Self {
+            id,
+            clients: clients.clone(),
+            in_round: in_round.clone(),
+            next_map: next_map.clone(),
+            rotation: maps.rotation(),
+            maps,
+            config,
+        };
+
+        let task = tokio::spawn(room.run());
+
+        RoomHandle {
+            id,
+            code,
+            clients,
+            in_round,
+            next_map,
+            task,
+            max_players,
+            player_count: 0,
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            self.clients.await_client().await;
+
+            let wait_time = self.config.room.lobby_wait;
+            log::info!(
+                "room {}: waiting {:?} for players to join",
+                self.id,
+                wait_time
+            );
+            tokio::time::sleep(wait_time).await;
+
+            let Some(map) = self.choose_map().await else {
+                log::error!("room {}: no maps available, skipping round", self.id);
+                continue;
+            };
+
+            self.in_round.store(true, Ordering::Relaxed);
+            self.play_round(&map).await;
+            self.in_round.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // let the players vote between a few maps, if there is more than one to choose from
+    async fn choose_map(&mut self) -> Option<Arc<MapEntry>> {
+        if let Some(map) = self.next_map.lock().unwrap().take() {
+            log::info!(
+                "room {}: playing '{}' as set by an admin",
+                self.id,
+                map.path
+            );
+            self.rotation.played(&map);
+            return Some(map);
+        }
+
+        let mut candidates = self
+            .rotation
+            .candidates(&self.maps.maps(), self.config.room.vote_candidates);
+        if candidates.len() <= 1 {
+            return candidates.pop();
+        }
+
+        log::info!("room {}: voting between {} maps", self.id, candidates.len());
+        let winner = self
+            .clients
+            .vote_map(
+                candidates.iter().map(|m| m.candidate()).collect(),
+                self.config.room.vote_duration,
+            )
+            .await;
+
+        let map = candidates.swap_remove(winner.min(candidates.len() - 1));
+        self.rotation.played(&map);
+        Some(map)
+    }
+
+    async fn play_round(&self, map: &MapEntry) {
+        log::info!(
+            "room {}: waiting for players to load map '{}'",
+            self.id,
+            map.path
+        );
+
+        let mut starting_clients = self.clients.load_map(&map.path, map.map.clone()).await;
+        starting_clients.shuffle(&mut rand::thread_rng());
+        self.clients.start_round(starting_clients.clone()).await;
+
+        log::info!("room {}: waiting for clients to load in", self.id);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        self.clients.send_race(ServerMessage::StartCountdown).await;
+        log::info!("room {}: waiting for race countdown to finish", self.id);
+        tokio::time::sleep(Duration::from_secs_f32(COUNTDOWN_DURATION)).await;
+
+        log::info!(
+            "room {}: round started with players: {:?}",
+            self.id,
+            starting_clients
+        );
+        self.clients.start_race().await;
+        let race_start = Instant::now();
+        let race_timeout = sleep(self.config.race.race_timeout);
+        tokio::pin!(race_timeout);
+
+        let mut tick_interval = interval(Duration::from_secs_f64(
+            1.0 / self.config.race.tick_rate as f64,
+        ));
+
+        loop {
+            tokio::select! {
+                _ = tick_interval.tick() => {
+                    let race_time = race_start.elapsed().as_secs_f32();
+                    let tick_start = Instant::now();
+                    let result = self.clients.game_tick(race_time).await;
+                    METRICS.tick(tick_start.elapsed());
+
+                    match result {
+                        TickResult::RaceOver => break,
+                        TickResult::NoChange => {}
+                    }
+                }
+                _ = &mut race_timeout => break,
+            }
+        }
+
+        if let Some(replay) = self.clients.complete_round().await {
+            self.save_replay(replay).await;
+        }
+    }
+
+    async fn save_replay(&self, replay: Replay) {
+        let config = self.config.clone();
+        let id = self.id;
+        let saved = tokio::task::spawn_blocking(move || save_replay(&config.replays, id, &replay))
+            .await
+            .unwrap();
+
+        match saved {
+            Ok(path) => log::info!("room {}: saved replay to '{}'", self.id, path.display()),
+            Err(e) => log::error!("room {}: failed to save replay: {}", self.id, e),
+        }
+    }
+}
+
+impl RoomHandle {
+    pub fn id(&self) -> RoomId {
+        self.id
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    pub fn clients(&self) -> &ClientManagerHandle {
+        &self.clients
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.player_count
+    }
+
+    pub fn in_round(&self) -> bool {
+        self.in_round.load(Ordering::Relaxed)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.player_count >= self.max_players
+    }
+
+    // matchmaking only puts players into public rooms that have space and aren't in the middle of a race
+    pub fn is_joinable(&self) -> bool {
+        self.code.is_none() && !self.is_full() && !self.in_round()
+    }
+
+    pub fn is_watchable(&self) -> bool {
+        self.code.is_none() && !self.is_full()
+    }
+
+    pub fn set_next_map(&self, map: Arc<MapEntry>) {
+        log::info!("room {}: next map set to '{}'", self.id, map.path);
+        *self.next_map.lock().unwrap() = Some(map);
+    }
+
+    pub fn player_joined(&mut self) {
+        self.player_count += 1;
+    }
+
+    pub fn player_left(&mut self) {
+        self.player_count = self.player_count.saturating_sub(1);
+    }
+
+    pub async fn shutdown(self) {
+        log::info!("room {}: shutting down", self.id);
+        self.task.abort();
+        self.clients.shutdown().await;
+    }
+}